@@ -1,15 +1,44 @@
-use crate::handlers::{dns, http, mtr, ping, tcping};
+use crate::errors::SocketIOError;
+use crate::handlers::{
+    bandwidth, dns, download, grpc, http, mtr, ntp, ping, pmtu, service, tcping, twamp, udp,
+    websocket,
+};
+use crate::job::Job;
+use crate::{mesh, metrics, netns, queue, status};
 use axum::body::Body;
+use axum::extract::Path;
 use axum::http::{header, StatusCode};
-use axum::response::Response;
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
-use axum::Router;
-use serde_json::Value;
+use axum::{Json, Router};
+use serde_json::{json, Value};
 use socketioxide::extract::{Data, SocketRef};
 use socketioxide::SocketIo;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{debug, error};
 
 static mut API_KEY: String = String::new();
 
+/// Events the agent can run jobs for, whichever way the request arrives.
+pub const EVENTS: [&str; 14] = [
+    "ping",
+    "tcping",
+    "dns",
+    "mtr",
+    "http",
+    "udp",
+    "ntp",
+    "pmtu",
+    "websocket",
+    "grpc",
+    "service",
+    "bandwidth",
+    "download",
+    "twamp",
+];
+
 pub fn create_app(api_key: String) -> Router {
     unsafe { API_KEY = api_key };
     let (layer, io) = SocketIo::new_layer();
@@ -17,13 +46,102 @@ pub fn create_app(api_key: String) -> Router {
     Router::new()
         .route("/", get(index))
         .route("/ping", get(pong_handler))
+        .route("/status", get(status_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/mesh", get(mesh_handler))
+        .route("/results/:job_id", get(results_handler))
         .layer(layer)
 }
 
+pub async fn run_job(job: Job, data: Value) {
+    let Some(name) = data["netns"].as_str() else {
+        return dispatch(job, data).await;
+    };
+    match netns::path(name) {
+        Some(path) => run_in_netns(job, data, path).await,
+        None => job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        })),
+    }
+}
+
+/// Runs the job on its own thread moved into the network namespace, the thread drives the job
+/// with a runtime of its own so every socket of the job is opened inside the namespace.
+async fn run_in_netns(job: Job, data: Value, path: PathBuf) {
+    let kind = job.kind();
+    // the job dies with the thread if it panics, its error is reported from here
+    let reporter = job.reporter();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let spawned = std::thread::Builder::new()
+        .name(format!("netns-{}", kind))
+        .spawn(move || {
+            let runtime = netns::enter(&path).and_then(|_| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+            });
+            match runtime {
+                Ok(runtime) => runtime.block_on(dispatch(job, data)),
+                Err(e) => {
+                    error!("enter netns {} failed: {}", path.display(), e);
+                    job.emit(json!({
+                        "error": SocketIOError::ErrNetnsFailed
+                    }));
+                }
+            }
+            let _ = tx.send(());
+        });
+    match spawned {
+        Ok(_) => {
+            if rx.await.is_err() {
+                error!("{} job in netns panicked", kind);
+                reporter.error(SocketIOError::ErrNetnsFailed);
+            }
+        }
+        Err(e) => {
+            error!("spawn netns thread failed: {}", e);
+            reporter.error(SocketIOError::ErrNetnsFailed);
+        }
+    }
+}
+
+async fn dispatch(job: Job, data: Value) {
+    match job.kind() {
+        "ping" => ping(job, data).await,
+        "tcping" => tcping(job, data).await,
+        "dns" => dns(job, data).await,
+        "mtr" => mtr(job, data).await,
+        "http" => http(job, data).await,
+        "udp" => udp(job, data).await,
+        "ntp" => ntp(job, data).await,
+        "pmtu" => pmtu(job, data).await,
+        "websocket" => websocket(job, data).await,
+        "grpc" => grpc(job, data).await,
+        "service" => service(job, data).await,
+        "bandwidth" => bandwidth(job, data).await,
+        "download" => download(job, data).await,
+        "twamp" => twamp(job, data).await,
+        _ => {}
+    }
+}
+
+fn authorized(headers: &header::HeaderMap) -> bool {
+    let key = headers
+        .get("authorization")
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.split(" ").nth(1));
+    let ok = match key {
+        Some(key) => key == { unsafe { &API_KEY } },
+        None => false,
+    };
+    if !ok {
+        metrics::AUTH_FAILURES.inc();
+    }
+    ok
+}
+
 async fn pong_handler(header: header::HeaderMap) -> Response<Body> {
-    let auth = header.get("authorization").unwrap().to_str().unwrap();
-    let key = auth.split(" ").collect::<Vec<&str>>()[1];
-    if key != { unsafe { &API_KEY } } {
+    if !authorized(&header) {
         Response::builder()
             .status(StatusCode::UNAUTHORIZED)
             .body(Body::empty())
@@ -36,51 +154,65 @@ async fn pong_handler(header: header::HeaderMap) -> Response<Body> {
     }
 }
 
+async fn status_handler(header: header::HeaderMap) -> Response<Body> {
+    if !authorized(&header) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(status::report()).into_response()
+}
+
+async fn metrics_handler(header: header::HeaderMap) -> Response<Body> {
+    if !authorized(&header) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+        .into_response()
+}
+
+async fn mesh_handler(header: header::HeaderMap) -> Response<Body> {
+    if !authorized(&header) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(mesh::report()).into_response()
+}
+
+async fn results_handler(header: header::HeaderMap, Path(job_id): Path<String>) -> Response<Body> {
+    if !authorized(&header) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(queue::take(&job_id)).into_response()
+}
+
 async fn index() -> &'static str {
     "Congratulations! You have successfully started the agent."
 }
 
 fn handler(socket: SocketRef, Data(_data): Data<Value>) {
-    let auth = socket
-        .req_parts()
-        .headers
-        .get("Authorization")
-        .unwrap()
-        .to_str()
-        .unwrap();
-    let key = auth.split(" ").collect::<Vec<&str>>()[1];
-    if key != { unsafe { &API_KEY } } {
+    if !authorized(&socket.req_parts().headers) {
         socket.emit("error", "unauthorized").unwrap();
         socket.disconnect().unwrap();
         return;
     }
-
-    socket.on(
-        "ping",
-        |socket: SocketRef, Data::<Value>(data)| async move {
-            ping(socket, data).await;
-        },
-    );
-
-    socket.on(
-        "tcping",
-        |socket: SocketRef, Data::<Value>(data)| async move {
-            tcping(socket, data).await;
-        },
-    );
-
-    socket.on("dns", |socket: SocketRef, Data::<Value>(data)| async move {
-        dns(socket, data).await;
+    metrics::SOCKET_CONNECTIONS.inc();
+    // one flag for every job of the socket, they are all cancelled when it goes away
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let disconnected = cancelled.clone();
+    socket.on_disconnect(move |_socket: SocketRef| {
+        metrics::SOCKET_CONNECTIONS.dec();
+        disconnected.store(true, Ordering::Relaxed);
+        debug!("socket disconnected, cancel its jobs");
     });
 
-    socket.on("mtr", |socket: SocketRef, Data::<Value>(data)| async move {
-        mtr(socket, data).await;
-    });
-
-    socket.on(
-        "http",
-        |socket: SocketRef, Data::<Value>(data)| async move {
-            http(socket, data).await;
-        },
-    );
+    for event in EVENTS {
+        let cancelled = cancelled.clone();
+        socket.on(
+            event,
+            move |socket: SocketRef, Data::<Value>(data)| async move {
+                run_job(Job::new(event, socket, cancelled, &data), data).await;
+            },
+        );
+    }
 }
@@ -0,0 +1,556 @@
+use crate::sockets::{self, Source};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{error, info};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Default port of the bandwidth server, the same as iperf3.
+pub const PORT: u16 = 5201;
+pub const MAX_DURATION: u64 = 60;
+pub const MAX_STREAMS: usize = 16;
+/// Bits per second a single stream may be asked to send, faster rates would pace datagrams at
+/// a gap that rounds to nothing.
+const MAX_STREAM_BITRATE: u64 = 100_000_000_000;
+/// Sequence number, send time and stream id at the start of every UDP datagram.
+const UDP_HEADER: usize = 20;
+const TCP_CHUNK: usize = 128 * 1024;
+/// Time the data connections or the first datagrams have to show up.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Datagrams still in flight when the sender stops.
+const UDP_GRACE: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Tcp,
+    Udp,
+}
+
+/// Seen from the agent running the job, upload sends data to the server.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Params {
+    pub mode: Mode,
+    pub direction: Direction,
+    /// Seconds.
+    pub duration: u64,
+    pub streams: usize,
+    /// Target rate in bits per second over all streams, UDP only.
+    pub bitrate: u64,
+    /// Datagram size, UDP only.
+    pub size: usize,
+}
+
+impl Params {
+    fn clamp(mut self) -> Params {
+        self.duration = self.duration.clamp(1, MAX_DURATION);
+        self.streams = self.streams.clamp(1, MAX_STREAMS);
+        self.size = self.size.clamp(UDP_HEADER, 65_000);
+        self.bitrate = self.bitrate.clamp(
+            8 * self.size as u64,
+            MAX_STREAM_BITRATE * self.streams as u64,
+        );
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    key: String,
+    params: Params,
+}
+
+/// What the receiving side measured.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Stats {
+    pub bytes: u64,
+    /// Seconds between the first and the last byte received.
+    pub duration: f64,
+    pub packets: Option<u64>,
+    pub lost: Option<u64>,
+    /// Milliseconds, RFC 3550 interarrival jitter averaged over the streams.
+    pub jitter: Option<f64>,
+}
+
+impl Stats {
+    pub fn bits_per_second(&self) -> f64 {
+        if self.duration <= 0.0 {
+            return 0.0;
+        }
+        self.bytes as f64 * 8.0 / self.duration
+    }
+
+    pub fn to_json(&self) -> Value {
+        let loss = match (self.packets, self.lost) {
+            (Some(packets), Some(lost)) if packets + lost > 0 => {
+                Some(lost as f64 * 100.0 / (packets + lost) as f64)
+            }
+            _ => None,
+        };
+        json!({
+            "bytes": self.bytes,
+            // milliseconds like the duration of every other probe
+            "duration": self.duration * 1000.0,
+            "bits_per_second": self.bits_per_second(),
+            "packets": self.packets,
+            "lost": self.lost,
+            "loss": loss,
+            "jitter": self.jitter,
+        })
+    }
+}
+
+fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Default)]
+struct UdpStream {
+    max_seq: Option<u64>,
+    received: u64,
+    last_transit: Option<f64>,
+    jitter: f64,
+}
+
+/// Counts datagrams per stream and tracks loss and jitter.
+#[derive(Default)]
+struct UdpReceiver {
+    streams: HashMap<u32, UdpStream>,
+    bytes: u64,
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl UdpReceiver {
+    fn on_packet(&mut self, packet: &[u8]) {
+        if packet.len() < UDP_HEADER {
+            return;
+        }
+        let now = Instant::now();
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+        self.bytes += packet.len() as u64;
+        let seq = u64::from_be_bytes(packet[0..8].try_into().unwrap());
+        let sent = u64::from_be_bytes(packet[8..16].try_into().unwrap());
+        let id = u32::from_be_bytes(packet[16..20].try_into().unwrap());
+        let stream = self.streams.entry(id).or_default();
+        stream.received += 1;
+        stream.max_seq = Some(stream.max_seq.map_or(seq, |max| max.max(seq)));
+        // only differences of the transit time matter, the clocks don't have to be in sync
+        let transit = unix_micros() as f64 / 1000.0 - sent as f64 / 1000.0;
+        if let Some(last) = stream.last_transit {
+            stream.jitter += ((transit - last).abs() - stream.jitter) / 16.0;
+        }
+        stream.last_transit = Some(transit);
+    }
+
+    fn stats(&self) -> Stats {
+        let packets = self.streams.values().map(|s| s.received).sum();
+        let lost = self
+            .streams
+            .values()
+            .map(|s| {
+                s.max_seq
+                    .map_or(0, |max| (max + 1).saturating_sub(s.received))
+            })
+            .sum();
+        let jitter = if self.streams.is_empty() {
+            0.0
+        } else {
+            self.streams.values().map(|s| s.jitter).sum::<f64>() / self.streams.len() as f64
+        };
+        let duration = match (self.first, self.last) {
+            (Some(first), Some(last)) => (last - first).as_secs_f64(),
+            _ => 0.0,
+        };
+        Stats {
+            bytes: self.bytes,
+            duration,
+            packets: Some(packets),
+            lost: Some(lost),
+            jitter: Some(jitter),
+        }
+    }
+}
+
+/// Sends paced datagrams for `duration`, to the connected peer or to `to`.
+async fn udp_send(
+    sock: Arc<UdpSocket>,
+    to: Option<SocketAddr>,
+    id: u32,
+    params: Params,
+) -> Result<(), Error> {
+    let rate = params.bitrate / params.streams as u64;
+    let gap = Duration::from_secs_f64(params.size as f64 * 8.0 / rate as f64);
+    let mut interval = time::interval(gap);
+    let mut packet = vec![0u8; params.size];
+    let deadline = Instant::now() + Duration::from_secs(params.duration);
+    let mut seq: u64 = 0;
+    while Instant::now() < deadline {
+        interval.tick().await;
+        packet[0..8].copy_from_slice(&seq.to_be_bytes());
+        packet[8..16].copy_from_slice(&unix_micros().to_be_bytes());
+        packet[16..20].copy_from_slice(&id.to_be_bytes());
+        let res = match to {
+            Some(to) => sock.send_to(&packet, to).await,
+            None => sock.send(&packet).await,
+        };
+        // a full socket buffer counts as loss rather than ending the test
+        if let Err(e) = res {
+            if e.kind() != std::io::ErrorKind::WouldBlock {
+                return Err(e.into());
+            }
+        }
+        seq += 1;
+    }
+    Ok(())
+}
+
+/// Receives datagrams until none arrived for `idle` or `stop` resolves.
+async fn udp_receive(
+    sock: &UdpSocket,
+    receiver: &mut UdpReceiver,
+    idle: Duration,
+    stop: impl std::future::Future<Output = ()>,
+) {
+    let mut buf = vec![0u8; 65_536];
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            res = time::timeout(idle, sock.recv(&mut buf)) => match res {
+                Ok(Ok(n)) => receiver.on_packet(&buf[..n]),
+                _ => return,
+            },
+            _ = &mut stop => return,
+        }
+    }
+}
+
+async fn tcp_send(mut stream: TcpStream, duration: Duration) -> Result<u64, Error> {
+    let chunk = vec![0u8; TCP_CHUNK];
+    let deadline = Instant::now() + duration;
+    let mut sent = 0;
+    while Instant::now() < deadline {
+        stream.write_all(&chunk).await?;
+        sent += chunk.len() as u64;
+    }
+    stream.shutdown().await?;
+    Ok(sent)
+}
+
+async fn tcp_receive(mut stream: TcpStream) -> Result<(u64, Instant, Instant), Error> {
+    let mut buf = vec![0u8; TCP_CHUNK];
+    let mut received = 0;
+    let mut first = None;
+    loop {
+        let n = stream.read(&mut buf).await?;
+        let now = Instant::now();
+        if n == 0 {
+            return Ok((received, first.unwrap_or(now), now));
+        }
+        first.get_or_insert(now);
+        received += n as u64;
+    }
+}
+
+/// Sums the bytes of all streams over the time from the first to the last byte.
+async fn tcp_receive_all(streams: Vec<TcpStream>) -> Result<Stats, Error> {
+    let mut set = JoinSet::new();
+    for stream in streams {
+        set.spawn(tcp_receive(stream));
+    }
+    let mut stats = Stats::default();
+    let mut first: Option<Instant> = None;
+    let mut last: Option<Instant> = None;
+    while let Some(res) = set.join_next().await {
+        let (bytes, start, end) = res??;
+        stats.bytes += bytes;
+        first = Some(first.map_or(start, |first| first.min(start)));
+        last = Some(last.map_or(end, |last| last.max(end)));
+    }
+    if let (Some(first), Some(last)) = (first, last) {
+        stats.duration = (last - first).as_secs_f64();
+    }
+    Ok(stats)
+}
+
+async fn tcp_send_all(streams: Vec<TcpStream>, duration: Duration) -> Result<u64, Error> {
+    let mut set = JoinSet::new();
+    for stream in streams {
+        set.spawn(tcp_send(stream, duration));
+    }
+    let mut sent = 0;
+    while let Some(res) = set.join_next().await {
+        sent += res??;
+    }
+    Ok(sent)
+}
+
+async fn write_line(stream: &mut (impl AsyncWrite + Unpin), value: &Value) -> Result<(), Error> {
+    stream.write_all(format!("{}\n", value).as_bytes()).await?;
+    Ok(())
+}
+
+async fn read_line(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Result<Value, Error> {
+    let line = lines
+        .next_line()
+        .await?
+        .ok_or("control connection closed")?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Listens for bandwidth tests from other agents, one test runs at a time.
+///
+/// A test starts on a control connection with `{"key": "...", "params": {...}}`, the reply
+/// `{"port": ...}` is the port the data streams connect or send to. The receiving side
+/// sends its measurements on the control connection when the test is over.
+pub async fn serve(port: u16, api_key: String) {
+    let listener = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("bandwidth server failed to listen on {}: {}", port, e);
+            return;
+        }
+    };
+    info!("bandwidth server listening on port {}", port);
+    let permits = Arc::new(Semaphore::new(1));
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(res) => res,
+            Err(e) => {
+                error!("bandwidth server accept failed: {}", e);
+                continue;
+            }
+        };
+        let api_key = api_key.clone();
+        let permits = permits.clone();
+        tokio::spawn(async move {
+            if let Err(e) = session(stream, &api_key, permits).await {
+                error!("bandwidth test from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn session(stream: TcpStream, api_key: &str, permits: Arc<Semaphore>) -> Result<(), Error> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let hello: Hello = serde_json::from_value(read_line(&mut lines).await?)?;
+    if hello.key != api_key {
+        write_line(&mut write, &json!({ "error": "unauthorized" })).await?;
+        return Ok(());
+    }
+    let _permit = match permits.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            write_line(&mut write, &json!({ "error": "busy" })).await?;
+            return Ok(());
+        }
+    };
+    let params = hello.params.clamp();
+    let duration = Duration::from_secs(params.duration);
+    match params.mode {
+        Mode::Tcp => {
+            let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)).await?;
+            let port = listener.local_addr()?.port();
+            write_line(&mut write, &json!({ "port": port })).await?;
+            let mut streams = Vec::new();
+            while streams.len() < params.streams {
+                let (stream, _) = time::timeout(SETUP_TIMEOUT, listener.accept()).await??;
+                streams.push(stream);
+            }
+            match params.direction {
+                Direction::Upload => {
+                    let stats = tcp_receive_all(streams).await?;
+                    write_line(&mut write, &serde_json::to_value(stats)?).await?;
+                }
+                Direction::Download => {
+                    let sent = tcp_send_all(streams, duration).await?;
+                    write_line(&mut write, &json!({ "sent": sent })).await?;
+                }
+            }
+        }
+        Mode::Udp => {
+            let sock = Arc::new(UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?);
+            let port = sock.local_addr()?.port();
+            write_line(&mut write, &json!({ "port": port })).await?;
+            match params.direction {
+                Direction::Upload => {
+                    let mut receiver = UdpReceiver::default();
+                    // the client says when it stopped sending
+                    let done = async {
+                        let _ = lines.next_line().await;
+                        time::sleep(UDP_GRACE).await;
+                    };
+                    udp_receive(&sock, &mut receiver, duration + SETUP_TIMEOUT, done).await;
+                    write_line(&mut write, &serde_json::to_value(receiver.stats())?).await?;
+                }
+                Direction::Download => {
+                    // every stream announces its address with a datagram before the test
+                    let mut peers = Vec::new();
+                    let mut buf = [0u8; 64];
+                    while peers.len() < params.streams {
+                        let (_, peer) =
+                            time::timeout(SETUP_TIMEOUT, sock.recv_from(&mut buf)).await??;
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                    let mut set = JoinSet::new();
+                    for (id, peer) in peers.into_iter().enumerate() {
+                        set.spawn(udp_send(
+                            sock.clone(),
+                            Some(peer),
+                            id as u32,
+                            params.clone(),
+                        ));
+                    }
+                    while let Some(res) = set.join_next().await {
+                        res??;
+                    }
+                    write_line(&mut write, &json!({ "done": true })).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a test against the bandwidth server at `server` from `source` and returns what the
+/// receiver measured.
+pub async fn run(
+    server: SocketAddr,
+    source: &Source,
+    key: &str,
+    params: Params,
+) -> Result<Stats, Error> {
+    let params = params.clamp();
+    let duration = Duration::from_secs(params.duration);
+    let control = sockets::tcp_connect(source, server).await?;
+    let (read, mut write) = control.into_split();
+    let mut lines = BufReader::new(read).lines();
+    write_line(&mut write, &json!({ "key": key, "params": params })).await?;
+    let reply = read_line(&mut lines).await?;
+    if let Some(e) = reply["error"].as_str() {
+        return Err(format!("server refused the test: {}", e).into());
+    }
+    let port = reply["port"].as_u64().ok_or("server didn't send a port")?;
+    let port = u16::try_from(port)?;
+    let data = SocketAddr::new(server.ip(), port);
+    match params.mode {
+        Mode::Tcp => {
+            let mut streams = Vec::new();
+            for _ in 0..params.streams {
+                streams.push(sockets::tcp_connect(source, data).await?);
+            }
+            match params.direction {
+                Direction::Upload => {
+                    tcp_send_all(streams, duration).await?;
+                    Ok(serde_json::from_value(read_line(&mut lines).await?)?)
+                }
+                Direction::Download => tcp_receive_all(streams).await,
+            }
+        }
+        Mode::Udp => {
+            let mut socks = Vec::new();
+            for _ in 0..params.streams {
+                socks.push(Arc::new(sockets::udp_connect(source, data).await?));
+            }
+            match params.direction {
+                Direction::Upload => {
+                    let mut set = JoinSet::new();
+                    for (id, sock) in socks.into_iter().enumerate() {
+                        set.spawn(udp_send(sock, None, id as u32, params.clone()));
+                    }
+                    while let Some(res) = set.join_next().await {
+                        res??;
+                    }
+                    write_line(&mut write, &json!({ "done": true })).await?;
+                    Ok(serde_json::from_value(read_line(&mut lines).await?)?)
+                }
+                Direction::Download => {
+                    let mut set = JoinSet::new();
+                    for sock in socks {
+                        sock.send(b"hello").await?;
+                        set.spawn(async move {
+                            let mut receiver = UdpReceiver::default();
+                            let stop = time::sleep(duration + SETUP_TIMEOUT);
+                            udp_receive(&sock, &mut receiver, SETUP_TIMEOUT, stop).await;
+                            receiver
+                        });
+                    }
+                    let mut total = UdpReceiver::default();
+                    while let Some(receiver) = set.join_next().await {
+                        let receiver = receiver?;
+                        total.bytes += receiver.bytes;
+                        total.streams.extend(receiver.streams);
+                        total.first = match (total.first, receiver.first) {
+                            (Some(a), Some(b)) => Some(a.min(b)),
+                            (a, b) => a.or(b),
+                        };
+                        total.last = total.last.max(receiver.last);
+                    }
+                    Ok(total.stats())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(streams: usize, bitrate: u64, size: usize) -> Params {
+        Params {
+            mode: Mode::Udp,
+            direction: Direction::Upload,
+            duration: 0,
+            streams,
+            bitrate,
+            size,
+        }
+        .clamp()
+    }
+
+    #[test]
+    fn clamp_limits() {
+        let p = params(0, 0, 0);
+        assert_eq!((p.duration, p.streams, p.size), (1, 1, UDP_HEADER));
+        assert_eq!(p.bitrate, 8 * UDP_HEADER as u64);
+        let p = params(100, u64::MAX, 100_000);
+        assert_eq!((p.streams, p.size), (MAX_STREAMS, 65_000));
+        assert_eq!(p.bitrate, MAX_STREAM_BITRATE * MAX_STREAMS as u64);
+        let p = params(4, 10_000_000, 1200);
+        assert_eq!((p.streams, p.bitrate, p.size), (4, 10_000_000, 1200));
+    }
+
+    #[test]
+    fn clamp_keeps_udp_gap() {
+        // the smallest datagram at the highest rate still paces at a gap above zero
+        let p = params(1, u64::MAX, 0);
+        let rate = p.bitrate / p.streams as u64;
+        let gap = Duration::from_secs_f64(p.size as f64 * 8.0 / rate as f64);
+        assert!(gap > Duration::ZERO);
+    }
+}
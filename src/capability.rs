@@ -0,0 +1,135 @@
+use crate::sockets::Source;
+use crate::traceroute;
+use rand::random;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::sync::OnceLock;
+use std::time::Duration;
+use surge_ping::{Client, Config, PingIdentifier, PingSequence, ICMP};
+use tracing::{debug, info};
+
+static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+
+/// The kind of ICMP socket the agent can open. Datagram sockets need no privileges on Linux
+/// when `net.ipv4.ping_group_range` includes the group of the agent.
+#[derive(Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IcmpSocket {
+    Raw,
+    Datagram,
+    #[default]
+    Unavailable,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct Capabilities {
+    pub icmp_socket_v4: IcmpSocket,
+    pub icmp_socket_v6: IcmpSocket,
+    pub icmp_v4: bool,
+    pub icmp_v6: bool,
+    pub traceroute: bool,
+    pub ipv6_egress: bool,
+}
+
+impl IcmpSocket {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IcmpSocket::Raw => "raw",
+            IcmpSocket::Datagram => "datagram",
+            IcmpSocket::Unavailable => "unavailable",
+        }
+    }
+}
+
+fn icmp_socket(domain: Domain, protocol: Protocol) -> IcmpSocket {
+    if Socket::new(domain, Type::RAW, Some(protocol)).is_ok() {
+        IcmpSocket::Raw
+    } else if Socket::new(domain, Type::DGRAM, Some(protocol)).is_ok() {
+        IcmpSocket::Datagram
+    } else {
+        IcmpSocket::Unavailable
+    }
+}
+
+async fn icmp(ip: IpAddr) -> bool {
+    let kind = if ip.is_ipv4() { ICMP::V4 } else { ICMP::V6 };
+    let client = match Client::new(&Config::builder().kind(kind).build()) {
+        Ok(client) => client,
+        Err(e) => {
+            debug!("icmp {} self-test failed: {}", ip, e);
+            return false;
+        }
+    };
+    let mut pinger = client.pinger(ip, PingIdentifier(random())).await;
+    pinger.timeout(Duration::from_secs(1));
+    match pinger.ping(PingSequence(0), &[0; 56]).await {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("icmp {} self-test failed: {}", ip, e);
+            false
+        }
+    }
+}
+
+async fn traceroute() -> bool {
+    let options = traceroute::Options {
+        first_hop: 1,
+        max_hops: 1,
+        timeout: Duration::from_secs(1),
+        flow: None,
+    };
+    let res = tokio::task::spawn_blocking(move || {
+        traceroute::trace(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            &options,
+            &Source::default(),
+            |_| true,
+        )
+    })
+    .await;
+    match res {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            debug!("traceroute self-test failed: {}", e);
+            false
+        }
+        Err(e) => {
+            debug!("traceroute self-test failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Connecting a UDP socket sends nothing but fails when there is no route.
+fn ipv6_egress() -> bool {
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.connect("[2001:4860:4860::8888]:53"))
+        .is_ok()
+}
+
+pub async fn self_test() -> Capabilities {
+    let capabilities = Capabilities {
+        icmp_socket_v4: icmp_socket(Domain::IPV4, Protocol::ICMPV4),
+        icmp_socket_v6: icmp_socket(Domain::IPV6, Protocol::ICMPV6),
+        icmp_v4: icmp(IpAddr::V4(Ipv4Addr::LOCALHOST)).await,
+        icmp_v6: icmp(IpAddr::V6(Ipv6Addr::LOCALHOST)).await,
+        traceroute: traceroute().await,
+        ipv6_egress: ipv6_egress(),
+    };
+    info!(
+        "self-test: icmp_socket_v4={}, icmp_socket_v6={}, icmp_v4={}, icmp_v6={}, traceroute={}, ipv6_egress={}",
+        capabilities.icmp_socket_v4.name(),
+        capabilities.icmp_socket_v6.name(),
+        capabilities.icmp_v4,
+        capabilities.icmp_v6,
+        capabilities.traceroute,
+        capabilities.ipv6_egress
+    );
+    CAPABILITIES.get_or_init(|| capabilities.clone());
+    capabilities
+}
+
+pub fn get() -> Capabilities {
+    CAPABILITIES.get().cloned().unwrap_or_default()
+}
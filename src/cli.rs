@@ -1,4 +1,6 @@
 use clap::Parser;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
 #[derive(Clone, Parser)]
 #[command(
@@ -32,4 +34,57 @@ pub struct Cli {
     /// Endpoint for agent to access, default is host ip:port, if you are behind proxy, you should set this to your public address
     #[arg(short, long, env = "NCA_ENDPOINT")]
     pub endpoint: Option<String>,
+    /// Probes to run periodically on the agent, like `http https://example.com every 30s`, results are exposed on `/metrics`
+    #[arg(long = "probe", env = "NCA_PROBES", value_delimiter = ';')]
+    pub probes: Vec<String>,
+    /// Directory to buffer results on disk when they can't be delivered, keyed by job id
+    #[arg(long, env = "NCA_QUEUE_DIR")]
+    pub queue_dir: Option<PathBuf>,
+    /// Results buffered at most, the oldest are dropped when more come in
+    #[arg(long, default_value_t = 100_000, env = "NCA_QUEUE_MAX")]
+    pub queue_max: usize,
+    /// Collector URL to push batched results to
+    #[arg(long, env = "NCA_PUSH_URL")]
+    pub push_url: Option<String>,
+    /// Interval in seconds between pushes to the collector
+    #[arg(long, default_value_t = 10, env = "NCA_PUSH_INTERVAL")]
+    pub push_interval: u64,
+    /// Websocket URL of the server to dial out to and receive jobs from, no inbound port is needed then
+    #[arg(long, env = "NCA_TUNNEL_URL")]
+    pub tunnel_url: Option<String>,
+    /// Port to serve bandwidth tests from other agents on, usually 5201, disabled when unset
+    #[arg(long, env = "NCA_BANDWIDTH_PORT")]
+    pub bandwidth_port: Option<u16>,
+    /// Port to reflect TWAMP-light test packets from other agents on, usually 862, disabled when unset
+    #[arg(long, env = "NCA_TWAMP_PORT")]
+    pub twamp_port: Option<u16>,
+    /// Other agents to probe in mesh mode, like `tokyo=1.2.3.4:4000`, the port defaults to our own
+    #[arg(long = "peer", env = "NCA_PEERS", value_delimiter = ';')]
+    pub peers: Vec<String>,
+    /// URL returning the mesh peers as a json array, fetched before every round
+    #[arg(long, env = "NCA_PEERS_URL")]
+    pub peers_url: Option<String>,
+    /// Interval in seconds between mesh probing rounds
+    #[arg(long, default_value_t = 60, env = "NCA_MESH_INTERVAL")]
+    pub mesh_interval: u64,
+    /// Probe types to run against every mesh peer, ping, tcping and udp are supported
+    #[arg(
+        long = "mesh-probe",
+        default_value = "ping,tcping,udp",
+        env = "NCA_MESH_PROBES",
+        value_delimiter = ','
+    )]
+    pub mesh_probes: Vec<String>,
+    /// ASN database to enrich ips in results with, a MaxMind `.mmdb` file or an ip2asn tsv file
+    #[arg(long, env = "NCA_ASN_DB")]
+    pub asn_db: Option<PathBuf>,
+    /// MaxMind GeoIP2 or GeoLite2 city or country database to enrich ips in results with
+    #[arg(long, env = "NCA_GEOIP_DB")]
+    pub geoip_db: Option<PathBuf>,
+    /// Source ip of probes whose request doesn't set `source_ip`
+    #[arg(long, env = "NCA_SOURCE_IP")]
+    pub source_ip: Option<IpAddr>,
+    /// Interface probes are bound to when their request doesn't set `interface`
+    #[arg(long, env = "NCA_INTERFACE")]
+    pub interface: Option<String>,
 }
@@ -1,11 +1,25 @@
+use crate::metrics;
 use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
 use hickory_resolver::lookup::Lookup;
 use hickory_resolver::TokioAsyncResolver;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use tracing::error;
+use std::time::Duration;
+use tokio::time;
+use tracing::{debug, error};
 
 pub async fn resolve(domain: &str, record_type: &str, nameserver: Option<&str>) -> Option<Lookup> {
+    resolve_from(domain, record_type, nameserver, None).await
+}
+
+/// Like `resolve`, queries to `nameserver` are sent from `bind_addr` when given.
+pub async fn resolve_from(
+    domain: &str,
+    record_type: &str,
+    nameserver: Option<&str>,
+    bind_addr: Option<SocketAddr>,
+) -> Option<Lookup> {
     let mut config = ResolverConfig::default();
     if !nameserver.is_none() {
         let mut ns = nameserver.unwrap().to_string();
@@ -21,14 +35,51 @@ pub async fn resolve(domain: &str, record_type: &str, nameserver: Option<&str>)
             protocol: Protocol::Udp,
             tls_dns_name: None,
             trust_negative_responses: false,
-            bind_addr: None,
+            bind_addr,
         });
     }
 
+    lookup(config, domain, record_type, nameserver.unwrap_or("system")).await
+}
+
+/// Like `resolve` without a nameserver, queries to each default nameserver are sent from the
+/// address `local_ip` picks for it and nameservers it has none for are left out.
+pub async fn resolve_bound(
+    domain: &str,
+    record_type: &str,
+    local_ip: impl Fn(IpAddr) -> io::Result<IpAddr>,
+) -> Option<Lookup> {
+    let mut config = ResolverConfig::new();
+    for ns in ResolverConfig::default().name_servers() {
+        match local_ip(ns.socket_addr.ip()) {
+            Ok(ip) => config.add_name_server(NameServerConfig {
+                bind_addr: Some(SocketAddr::new(ip, 0)),
+                ..ns.clone()
+            }),
+            Err(e) => debug!("skip nameserver {}: {}", ns.socket_addr, e),
+        }
+    }
+    if config.name_servers().is_empty() {
+        error!(
+            "dns resolve failed: no nameserver reachable from the source, domain: {}",
+            domain
+        );
+        return None;
+    }
+    lookup(config, domain, record_type, "system").await
+}
+
+async fn lookup(
+    config: ResolverConfig,
+    domain: &str,
+    record_type: &str,
+    nameserver: &str,
+) -> Option<Lookup> {
     let resolver = TokioAsyncResolver::tokio(config, ResolverOpts::default());
     match resolver.lookup(domain, record_type.parse().unwrap()).await {
         Ok(res) => Some(res),
         Err(e) => {
+            metrics::DNS_FAILURES.with_label_values(&[nameserver]).inc();
             error!(
                 "dns resolve failed: {}, domain: {}, record_type: {}",
                 e, domain, record_type
@@ -37,3 +88,44 @@ pub async fn resolve(domain: &str, record_type: &str, nameserver: Option<&str>)
         }
     }
 }
+
+/// Resolves `host` to its first address of the wanted family, IP addresses are returned as is.
+pub async fn resolve_ip(host: &str, is_ipv4: bool, nameserver: Option<&str>) -> Option<IpAddr> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    let record_type = if is_ipv4 { "A" } else { "AAAA" };
+    resolve(host, record_type, nameserver)
+        .await?
+        .iter()
+        .find_map(|record| record.to_string().parse().ok())
+}
+
+/// How long a reverse lookup may hold up the result it names.
+const REVERSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reverse lookups of one job, sharing the system resolver and its cache between them.
+#[derive(Clone)]
+pub struct Reverse {
+    resolver: TokioAsyncResolver,
+}
+
+impl Reverse {
+    pub fn new() -> Reverse {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
+            error!("failed to read the system resolver config: {}", e);
+            TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+        });
+        Reverse { resolver }
+    }
+
+    /// The PTR name of `ip` without the trailing dot, `None` if there is none in time.
+    pub async fn lookup(&self, ip: IpAddr) -> Option<String> {
+        let names = time::timeout(REVERSE_TIMEOUT, self.resolver.reverse_lookup(ip))
+            .await
+            .ok()?
+            .ok()?;
+        let name = names.iter().next()?.to_string();
+        Some(name.trim_end_matches('.').to_string())
+    }
+}
@@ -10,6 +10,58 @@ pub enum SocketIOError {
     ErrTCPingFailed,
     #[serde(rename(serialize = "err_http_failed"))]
     ErrHTTPFailed,
+    #[serde(rename(serialize = "err_http_version_mismatch"))]
+    ErrHTTPVersionMismatch,
     #[serde(rename(serialize = "err_mtr_failed"))]
     ErrMTRFailed,
+    #[serde(rename(serialize = "err_unknown_event"))]
+    ErrUnknownEvent,
+    #[serde(rename(serialize = "err_invalid_request"))]
+    ErrInvalidRequest,
+    #[serde(rename(serialize = "err_udp_failed"))]
+    ErrUDPFailed,
+    #[serde(rename(serialize = "err_udp_timeout"))]
+    ErrUDPTimeout,
+    #[serde(rename(serialize = "err_udp_response_mismatch"))]
+    ErrUDPResponseMismatch,
+    #[serde(rename(serialize = "err_ntp_failed"))]
+    ErrNTPFailed,
+    #[serde(rename(serialize = "err_ntp_timeout"))]
+    ErrNTPTimeout,
+    #[serde(rename(serialize = "err_ntp_kiss_of_death"))]
+    ErrNTPKissOfDeath,
+    #[serde(rename(serialize = "err_pmtu_failed"))]
+    ErrPMTUFailed,
+    #[serde(rename(serialize = "err_websocket_failed"))]
+    ErrWebSocketFailed,
+    #[serde(rename(serialize = "err_websocket_timeout"))]
+    ErrWebSocketTimeout,
+    #[serde(rename(serialize = "err_grpc_failed"))]
+    ErrGRPCFailed,
+    #[serde(rename(serialize = "err_grpc_not_serving"))]
+    ErrGRPCNotServing,
+    #[serde(rename(serialize = "err_service_failed"))]
+    ErrServiceFailed,
+    #[serde(rename(serialize = "err_service_timeout"))]
+    ErrServiceTimeout,
+    #[serde(rename(serialize = "err_service_bad_greeting"))]
+    ErrServiceBadGreeting,
+    #[serde(rename(serialize = "err_starttls_failed"))]
+    ErrStartTLSFailed,
+    #[serde(rename(serialize = "err_bandwidth_failed"))]
+    ErrBandwidthFailed,
+    #[serde(rename(serialize = "err_download_failed"))]
+    ErrDownloadFailed,
+    #[serde(rename(serialize = "err_download_hash_mismatch"))]
+    ErrDownloadHashMismatch,
+    #[serde(rename(serialize = "err_download_incomplete"))]
+    ErrDownloadIncomplete,
+    #[serde(rename(serialize = "err_twamp_failed"))]
+    ErrTWAMPFailed,
+    #[serde(rename(serialize = "err_twamp_timeout"))]
+    ErrTWAMPTimeout,
+    #[serde(rename(serialize = "err_unsupported"))]
+    ErrUnsupported,
+    #[serde(rename(serialize = "err_netns_failed"))]
+    ErrNetnsFailed,
 }
@@ -0,0 +1,180 @@
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+pub const ICMP_DEST_UNREACH: u8 = 3;
+pub const ICMP_PORT_UNREACH: u8 = 3;
+pub const ICMP_FRAG_NEEDED: u8 = 4;
+pub const ICMP6_DST_UNREACH: u8 = 1;
+pub const ICMP6_PACKET_TOO_BIG: u8 = 2;
+pub const ICMP6_PORT_UNREACH: u8 = 4;
+
+pub struct ExtendedError {
+    pub errno: i32,
+    pub origin: u8,
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    /// Next hop MTU for "fragmentation needed" and "packet too big".
+    pub info: u32,
+    /// The host that sent the ICMP error.
+    pub offender: Option<IpAddr>,
+}
+
+impl ExtendedError {
+    pub fn is_port_unreachable(&self) -> bool {
+        match self.origin {
+            libc::SO_EE_ORIGIN_ICMP => {
+                self.icmp_type == ICMP_DEST_UNREACH && self.icmp_code == ICMP_PORT_UNREACH
+            }
+            libc::SO_EE_ORIGIN_ICMP6 => {
+                self.icmp_type == ICMP6_DST_UNREACH && self.icmp_code == ICMP6_PORT_UNREACH
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_too_big(&self) -> bool {
+        match self.origin {
+            libc::SO_EE_ORIGIN_ICMP => {
+                self.icmp_type == ICMP_DEST_UNREACH && self.icmp_code == ICMP_FRAG_NEEDED
+            }
+            libc::SO_EE_ORIGIN_ICMP6 => self.icmp_type == ICMP6_PACKET_TOO_BIG,
+            libc::SO_EE_ORIGIN_LOCAL => self.errno == libc::EMSGSIZE,
+            _ => false,
+        }
+    }
+}
+
+pub fn setsockopt(fd: RawFd, level: i32, name: i32, value: i32) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const i32 as *const libc::c_void,
+            mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn getsockopt(fd: RawFd, level: i32, name: i32) -> io::Result<i32> {
+    let mut value: i32 = 0;
+    let mut len = mem::size_of::<i32>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut i32 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Turns on `IP_RECVERR`, ICMP errors for the socket are then queued and read by `recv`.
+pub fn enable(fd: RawFd, is_ipv4: bool) -> io::Result<()> {
+    if is_ipv4 {
+        setsockopt(fd, libc::IPPROTO_IP, libc::IP_RECVERR, 1)
+    } else {
+        setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1)
+    }
+}
+
+pub enum Readiness {
+    Data,
+    Error,
+    Timeout,
+}
+
+pub fn wait(fd: RawFd, timeout: Duration) -> io::Result<Readiness> {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let res = unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if res == 0 {
+        return Ok(Readiness::Timeout);
+    }
+    if pfd.revents & libc::POLLERR != 0 {
+        return Ok(Readiness::Error);
+    }
+    Ok(Readiness::Data)
+}
+
+fn offender(ee: *const libc::sock_extended_err) -> Option<IpAddr> {
+    // SO_EE_OFFENDER: the address follows the extended error
+    let addr = unsafe { ee.add(1) as *const u8 };
+    let family = unsafe { std::ptr::read_unaligned(addr as *const libc::sa_family_t) } as i32;
+    match family {
+        libc::AF_INET => {
+            let addr = unsafe { std::ptr::read_unaligned(addr as *const libc::sockaddr_in) };
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                addr.sin_addr.s_addr,
+            ))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { std::ptr::read_unaligned(addr as *const libc::sockaddr_in6) };
+            Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+/// Pops one error from the queue, `payload` receives the datagram that caused it.
+pub fn recv(fd: RawFd, payload: &mut [u8]) -> io::Result<Option<ExtendedError>> {
+    let mut control = [0u8; 512];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let res = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if (level == libc::IPPROTO_IP && kind == libc::IP_RECVERR)
+            || (level == libc::IPPROTO_IPV6 && kind == libc::IPV6_RECVERR)
+        {
+            let ee = unsafe { libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err };
+            let e = unsafe { std::ptr::read_unaligned(ee) };
+            return Ok(Some(ExtendedError {
+                errno: e.ee_errno as i32,
+                origin: e.ee_origin,
+                icmp_type: e.ee_type,
+                icmp_code: e.ee_code,
+                info: e.ee_info,
+                offender: offender(ee),
+            }));
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok(None)
+}
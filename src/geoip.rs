@@ -0,0 +1,265 @@
+use maxminddb::{geoip2, Reader};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::info;
+
+/// Job types whose results get their ips enriched.
+const KINDS: [&str; 4] = ["ping", "tcping", "mtr", "http"];
+/// Result fields holding an ip, nested objects like the http redirect chain are looked into too.
+const IP_FIELDS: [&str; 2] = ["ip", "ip_addr"];
+
+static DATABASES: OnceLock<Databases> = OnceLock::new();
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+struct Databases {
+    asn: Option<Asn>,
+    geo: Option<Reader<Vec<u8>>>,
+}
+
+enum Asn {
+    Mmdb(Reader<Vec<u8>>),
+    /// Ranges of an ip2asn tsv file sorted by start, ipv4 is mapped into ipv6.
+    Ranges(Vec<Range>),
+}
+
+struct Range {
+    start: u128,
+    end: u128,
+    asn: u32,
+    country: Option<String>,
+    name: String,
+}
+
+#[derive(Default)]
+struct Info {
+    asn: Option<u32>,
+    as_name: Option<String>,
+    country: Option<String>,
+    city: Option<String>,
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// Parses an ip2asn tsv file: range start, range end, AS number, country code and AS description.
+fn parse_ranges(content: &str) -> Vec<Range> {
+    let mut ranges: Vec<Range> = content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let start: IpAddr = fields.next()?.parse().ok()?;
+            let end: IpAddr = fields.next()?.parse().ok()?;
+            let asn: u32 = fields.next()?.parse().ok()?;
+            let country = fields.next()?;
+            let name = fields.next().unwrap_or_default();
+            // AS 0 marks ranges not routed by anyone
+            if asn == 0 {
+                return None;
+            }
+            Some(Range {
+                start: to_u128(start),
+                end: to_u128(end),
+                asn,
+                country: match country {
+                    "" | "None" | "Unknown" => None,
+                    country => Some(country.to_string()),
+                },
+                name: name.to_string(),
+            })
+        })
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+    ranges
+}
+
+fn open_asn(path: &Path) -> Result<Asn, Error> {
+    if path.extension().is_some_and(|ext| ext == "mmdb") {
+        return Ok(Asn::Mmdb(Reader::open_readfile(path)?));
+    }
+    let ranges = parse_ranges(&fs::read_to_string(path)?);
+    if ranges.is_empty() {
+        return Err(format!("no ip2asn ranges found in {}", path.display()).into());
+    }
+    Ok(Asn::Ranges(ranges))
+}
+
+/// Loads the databases enrichment is done from, results are left alone when none is given.
+pub fn init(asn: Option<&Path>, geo: Option<&Path>) -> Result<(), Error> {
+    if asn.is_none() && geo.is_none() {
+        return Ok(());
+    }
+    let databases = Databases {
+        asn: asn.map(open_asn).transpose()?,
+        geo: geo.map(Reader::<Vec<u8>>::open_readfile).transpose()?,
+    };
+    info!(
+        "enrich results with{}{}",
+        asn.map(|p| format!(" asn from {}", p.display()))
+            .unwrap_or_default(),
+        geo.map(|p| format!(" geoip from {}", p.display()))
+            .unwrap_or_default()
+    );
+    DATABASES.get_or_init(|| databases);
+    Ok(())
+}
+
+fn english(names: Option<&BTreeMap<&str, &str>>) -> Option<String> {
+    names
+        .and_then(|names| names.get("en"))
+        .map(|name| name.to_string())
+}
+
+impl Databases {
+    fn lookup(&self, ip: IpAddr) -> Info {
+        let mut info = Info::default();
+        match &self.asn {
+            Some(Asn::Mmdb(reader)) => {
+                if let Ok(asn) = reader.lookup::<geoip2::Asn>(ip) {
+                    info.asn = asn.autonomous_system_number;
+                    info.as_name = asn.autonomous_system_organization.map(String::from);
+                }
+            }
+            Some(Asn::Ranges(ranges)) => {
+                let ip = to_u128(ip);
+                let idx = ranges.partition_point(|range| range.start <= ip);
+                if let Some(range) = idx.checked_sub(1).map(|idx| &ranges[idx]) {
+                    if ip <= range.end {
+                        info.asn = Some(range.asn);
+                        info.as_name = Some(range.name.clone());
+                        info.country = range.country.clone();
+                    }
+                }
+            }
+            None => {}
+        }
+        // city databases answer country lookups too
+        if let Some(Ok(city)) = self.geo.as_ref().map(|geo| geo.lookup::<geoip2::City>(ip)) {
+            if let Some(country) = city.country.and_then(|country| country.iso_code) {
+                info.country = Some(country.to_string());
+            }
+            info.city = english(city.city.and_then(|city| city.names).as_ref());
+        }
+        info
+    }
+
+    fn enrich_object(&self, object: &mut Map<String, Value>) {
+        let ip = IP_FIELDS
+            .iter()
+            .find_map(|field| object.get(*field)?.as_str()?.parse::<IpAddr>().ok());
+        if let Some(ip) = ip {
+            let info = self.lookup(ip);
+            object.insert("asn".to_string(), json!(info.asn));
+            object.insert("as_name".to_string(), json!(info.as_name));
+            object.insert("country".to_string(), json!(info.country));
+            object.insert("city".to_string(), json!(info.city));
+        }
+        for value in object.values_mut() {
+            if let Value::Array(items) = value {
+                for item in items {
+                    if let Value::Object(item) = item {
+                        self.enrich_object(item);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Attaches ASN, AS name, country and city to the ips in a result of a supported job type.
+pub fn enrich(kind: &str, result: &mut Value) {
+    let Some(databases) = DATABASES.get() else {
+        return;
+    };
+    if !KINDS.contains(&kind) {
+        return;
+    }
+    if let Value::Object(object) = result {
+        databases.enrich_object(object);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TSV: &str = "\
+1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET
+1.0.4.0\t1.0.7.255\t38803\tAU\tWPL-AS-AP Wirelessconnect
+10.0.0.0\t10.255.255.255\t0\tNone\tNot routed
+255.255.255.0\t255.255.255.255\t64500\tNone\tLAST-V4
+not an ip\t1.1.1.1\t1\tUS\tBROKEN
+::ffff:0:0\t::ffff:0:ff\t64501\tUnknown\tMAPPED
+2001:db8::\t2001:db8::ffff\t64496\tDE\tDOC-V6
+";
+
+    fn databases() -> Databases {
+        Databases {
+            asn: Some(Asn::Ranges(parse_ranges(TSV))),
+            geo: None,
+        }
+    }
+
+    fn asn(databases: &Databases, ip: &str) -> Option<u32> {
+        databases.lookup(ip.parse().unwrap()).asn
+    }
+
+    #[test]
+    fn parse_tsv() {
+        let ranges = parse_ranges(TSV);
+        // AS 0 and unparseable lines are skipped, the rest sorted by start
+        assert_eq!(ranges.len(), 5);
+        assert!(ranges.windows(2).all(|pair| pair[0].start < pair[1].start));
+        assert_eq!(ranges[0].asn, 64501);
+        assert_eq!(ranges[0].country, None);
+        let cloudflare = ranges.iter().find(|range| range.asn == 13335).unwrap();
+        assert_eq!(cloudflare.country.as_deref(), Some("US"));
+        assert_eq!(cloudflare.name, "CLOUDFLARENET");
+        assert!(parse_ranges("").is_empty());
+    }
+
+    #[test]
+    fn lookup_ranges() {
+        let databases = databases();
+        let info = databases.lookup("1.0.5.1".parse().unwrap());
+        assert_eq!(info.asn, Some(38803));
+        assert_eq!(info.as_name.as_deref(), Some("WPL-AS-AP Wirelessconnect"));
+        assert_eq!(info.country.as_deref(), Some("AU"));
+        assert_eq!(info.city, None);
+        assert_eq!(asn(&databases, "2001:db8::1"), Some(64496));
+    }
+
+    #[test]
+    fn lookup_edges() {
+        let databases = databases();
+        // both ends of a range are in it
+        assert_eq!(asn(&databases, "1.0.0.0"), Some(13335));
+        assert_eq!(asn(&databases, "1.0.0.255"), Some(13335));
+        // the gap between two ranges and the skipped AS 0 range
+        assert_eq!(asn(&databases, "1.0.1.0"), None);
+        assert_eq!(asn(&databases, "10.1.2.3"), None);
+        // before the first and after the last range
+        assert_eq!(asn(&databases, "::1"), None);
+        assert_eq!(asn(&databases, "2001:db8::1:0"), None);
+        assert_eq!(asn(&databases, "255.255.255.255"), Some(64500));
+    }
+
+    #[test]
+    fn lookup_mapped_boundary() {
+        let databases = databases();
+        // ipv4 lives in ::ffff:0:0/96, an ipv4 range and its mapped ipv6 form are the same
+        assert_eq!(asn(&databases, "0.0.0.255"), Some(64501));
+        assert_eq!(asn(&databases, "::ffff:0:ff"), Some(64501));
+        assert_eq!(asn(&databases, "::ffff:1.0.0.1"), Some(13335));
+        assert_eq!(asn(&databases, "::fffe:ffff:ffff"), None);
+        assert_eq!(asn(&databases, "::1:0:0:0"), None);
+    }
+}
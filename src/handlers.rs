@@ -1,52 +1,146 @@
+use crate::bandwidth;
 use crate::dns;
 use crate::errors::SocketIOError;
+use crate::http;
+use crate::job::Job;
+use crate::ntp;
+use crate::pmtu;
+use crate::service;
+use crate::sockets::{self, Source};
+use crate::traceroute;
+use crate::twamp;
+use crate::udp;
 use crate::utils::is_ip;
+use futures_util::{SinkExt, StreamExt};
+use hyper_util::rt::TokioIo;
 use rand::random;
 use serde_json::{json, Value};
-use socketioxide::extract::Bin;
-use socketioxide::extract::Data;
-use socketioxide::extract::SocketRef;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::{Entry, HashMap};
 use std::net::IpAddr;
 use std::net::SocketAddr;
-use std::thread;
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
 use surge_ping::{Client, Config, IcmpPacket, PingIdentifier, PingSequence, ICMP};
-use tokio::net;
-use tokio::sync::mpsc;
 use tokio::time;
-use tracert::trace::Tracer;
+use tokio_tungstenite::client_async_tls_with_config;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{
+    HeaderName as WsHeaderName, HeaderValue as WsHeaderValue,
+};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tonic::metadata::MetadataMap;
+use tonic::transport::{ClientTlsConfig, Endpoint, Uri};
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+use tower::service_fn;
 use tracing::debug;
 use tracing::error;
 use url::Url;
 
-pub async fn ping(socket: SocketRef, data: Value) {
-    let (tx, mut rx) = mpsc::channel::<bool>(1);
-    socket.on(
-        "disconnect",
-        |_socket: SocketRef, Data::<Value>(_data), Bin(_bin)| async move {
-            tx.send(true).await.unwrap();
-            debug!("ping disconnect by client");
-        },
-    );
+/// Missing privileges or platform support mean no probe of the kind can run on this agent,
+/// which is reported apart from probes failing on the target.
+fn unsupported_or(e: &std::io::Error, err: SocketIOError) -> SocketIOError {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::Unsupported => {
+            SocketIOError::ErrUnsupported
+        }
+        _ => err,
+    }
+}
+
+/// The `port` of a request or `default`, `None` when it is set but not a port.
+fn request_port(data: &Value, default: u16) -> Option<u16> {
+    if data["port"].is_null() {
+        return Some(default);
+    }
+    data["port"]
+        .as_u64()
+        .and_then(|port| u16::try_from(port).ok())
+}
+
+/// Like `source` for clients that can only bind an address, a `vrf` is refused as unsupported
+/// since only binding its device picks the routing table of the VRF.
+fn address_source(job: &Job, data: &Value) -> Option<Source> {
+    if !data["vrf"].is_null() {
+        job.emit(json!({
+            "error": SocketIOError::ErrUnsupported
+        }));
+        return None;
+    }
+    source(job, data)
+}
+
+/// Reverse lookups for the results of the job, requests opt out with `"reverse": false`.
+fn reverse(data: &Value) -> Option<dns::Reverse> {
+    data["reverse"]
+        .as_bool()
+        .unwrap_or(true)
+        .then(dns::Reverse::new)
+}
+
+/// Adds the PTR name of the `ip` of `result` and of its redirect chain as `host_name`.
+async fn add_host_names(reverse: Option<&dns::Reverse>, result: &mut Value) {
+    let Some(reverse) = reverse else {
+        return;
+    };
+    let ip = |object: &Value| object["ip"].as_str()?.parse::<IpAddr>().ok();
+    let mut names = HashMap::new();
+    let chain = result["chain"].as_array().into_iter().flatten();
+    for ip in std::iter::once(&*result).chain(chain).filter_map(ip) {
+        if let Entry::Vacant(entry) = names.entry(ip) {
+            entry.insert(reverse.lookup(ip).await);
+        }
+    }
+    let name = |object: &mut Value| {
+        if let Some(ip) = ip(&*object) {
+            object["host_name"] = json!(names[&ip]);
+        }
+    };
+    name(&mut *result);
+    if let Some(chain) = result.get_mut("chain").and_then(Value::as_array_mut) {
+        chain.iter_mut().for_each(name);
+    }
+}
+
+/// The source of the probe over the agent defaults, an invalid `source_ip` is reported on the
+/// job.
+fn source(job: &Job, data: &Value) -> Option<Source> {
+    let source = Source::from_request(data);
+    if source.is_none() {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+    }
+    source
+}
+
+pub async fn ping(job: Job, data: Value) {
     debug!("receive ping request: {}", data);
-    let host = data["host"].as_str().unwrap();
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
     let single = data["single"].as_bool().unwrap_or(true);
     let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
     let ns: Option<&str> = data["ns"].as_str();
     let record_type: &str = if is_ipv4 { "A" } else { "AAAA" };
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
     let ip = if is_ip(host) {
         host.to_string()
     } else {
         let res = dns::resolve(host, record_type, ns).await;
         if res.is_none() {
-            socket
-                .emit(
-                    "ping",
-                    json!({
-                        "error":SocketIOError::ErrDNSLookupFailed
-                    }),
-                )
-                .unwrap();
+            job.emit(json!({
+                "error":SocketIOError::ErrDNSLookupFailed
+            }));
             return;
         }
         res.unwrap()
@@ -62,8 +156,36 @@ pub async fn ping(socket: SocketRef, data: Value) {
     } else {
         config_builder = config_builder.kind(ICMP::V6);
     }
+    if source.ip.is_some() {
+        match source.local(ip.parse().unwrap()) {
+            Ok(local) => config_builder = config_builder.bind(local),
+            Err(e) => {
+                error!("ping {} failed: {}", host, e);
+                job.emit(json!({
+                    "error": SocketIOError::ErrPingFailed
+                }));
+                return;
+            }
+        }
+    }
+    if let Some(interface) = &source.interface {
+        config_builder = config_builder.interface(interface);
+    }
     let config = config_builder.build();
-    let client = Client::new(&config).unwrap();
+    let client = match Client::new(&config) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("ping {} failed: {}", host, e);
+            job.emit(json!({
+                "error": unsupported_or(&e, SocketIOError::ErrPingFailed)
+            }));
+            return;
+        }
+    };
+    let host_name = match reverse(&data) {
+        Some(reverse) => reverse.lookup(ip.parse().unwrap()).await,
+        None => None,
+    };
     let payload = [0; 56];
     let mut pinger = client
         .pinger(ip.parse().unwrap(), PingIdentifier(random()))
@@ -72,79 +194,76 @@ pub async fn ping(socket: SocketRef, data: Value) {
     pinger.timeout(Duration::from_secs(1));
     let times = if single { 1 } else { 100 };
     for idx in 0..times {
-        if rx.try_recv().is_ok() {
+        if job.cancelled() {
             return;
         }
         interval.tick().await;
         match pinger.ping(PingSequence(idx), &payload).await {
-            Ok((IcmpPacket::V4(packet), dur)) => socket
-                .emit(
-                    "ping",
-                    json!({
-                        "ip": packet.get_source(),
-                        "duration": Some(dur).map(|d| d.as_millis()),
-                        "seq": packet.get_sequence().0+1
-                    }),
-                )
-                .unwrap(),
-            Ok((IcmpPacket::V6(packet), dur)) => socket
-                .emit(
-                    "ping",
-                    json!({
-                        "ip": packet.get_source(),
-                        "duration": Some(dur).map(|d| d.as_millis()),
-                        "seq": packet.get_sequence().0+1
-                    }),
-                )
-                .unwrap(),
+            Ok((IcmpPacket::V4(packet), dur)) => job.emit(json!({
+                "ip": packet.get_source(),
+                "host_name": host_name,
+                "duration": dur.as_secs_f64() * 1000.0,
+                "seq": packet.get_sequence().0+1
+            })),
+            Ok((IcmpPacket::V6(packet), dur)) => job.emit(json!({
+                "ip": packet.get_source(),
+                "host_name": host_name,
+                "duration": dur.as_secs_f64() * 1000.0,
+                "seq": packet.get_sequence().0+1
+            })),
             Err(e) => {
                 error!("ping {} failed: {}", host, e);
-                socket
-                    .emit(
-                        "ping",
-                        json!({
-                            "ip": ip,
-                            "duration": None::<u64>,
-                            "seq": idx+1,
-                            "error": SocketIOError::ErrPingFailed,
-                        }),
-                    )
-                    .unwrap()
+                job.emit(json!({
+                    "ip": ip,
+                    "host_name": host_name,
+                    "duration": None::<u64>,
+                    "seq": idx+1,
+                    "error": SocketIOError::ErrPingFailed,
+                }))
             }
         }
     }
-    socket.disconnect().unwrap();
+    job.finish();
 }
 
-pub async fn tcping(socket: SocketRef, data: Value) {
-    let (tx, mut rx) = mpsc::channel::<bool>(1);
-    socket.on(
-        "disconnect",
-        |_socket: SocketRef, Data::<Value>(_data)| async move {
-            tx.send(true).await.unwrap();
-            debug!("tcping disconnect by client");
-        },
-    );
+pub async fn tcping(job: Job, data: Value) {
     debug!("receive tcping request: {}", data);
-    let host = data["host"].as_str().unwrap();
-    let domain = host.split(":").next().unwrap();
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    // host:port, ipv6 addresses come in brackets like [::1]:80
+    let domain = host.rsplit_once(':').map_or(host, |(domain, _)| domain);
+    let domain = domain.trim_start_matches('[').trim_end_matches(']');
     let single = data["single"].as_bool().unwrap_or(true);
     let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
     let ns: Option<&str> = data["ns"].as_str();
     let record_type = if is_ipv4 { "A" } else { "AAAA" };
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
+    let port: u16 = match host
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+    {
+        Some(port) => port,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrInvalidRequest
+            }));
+            return;
+        }
+    };
     let ip = if is_ip(domain) {
         domain.to_string()
     } else {
         let res = dns::resolve(domain, record_type, ns).await;
         if res.is_none() {
-            socket
-                .emit(
-                    "tcping",
-                    json!({
-                        "error": SocketIOError::ErrDNSLookupFailed
-                    }),
-                )
-                .unwrap();
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
             return;
         }
         res.unwrap()
@@ -154,54 +273,82 @@ pub async fn tcping(socket: SocketRef, data: Value) {
             .unwrap()
             .to_string()
     };
+    let addr = SocketAddr::new(ip.parse().unwrap(), port);
+    let host_name = match reverse(&data) {
+        Some(reverse) => reverse.lookup(addr.ip()).await,
+        None => None,
+    };
     let times = if single { 1 } else { 100 };
     let mut interval = time::interval(Duration::from_secs(1));
     for idx in 0..times {
-        if rx.try_recv().is_ok() {
+        if job.cancelled() {
             return;
         }
         interval.tick().await;
         let start = std::time::Instant::now();
-        let res = net::TcpStream::connect(host).await;
+        let res = sockets::tcp_connect(&source, addr).await;
         match res {
             Ok(_) => {
-                let ms = start.elapsed().as_millis();
-                socket
-                    .emit(
-                        "tcping",
-                        json!({
-                            "ip": ip,
-                            "duration": ms,
-                            "seq": idx+1
-                        }),
-                    )
-                    .unwrap();
+                let ms = start.elapsed().as_secs_f64() * 1000.0;
+                job.emit(json!({
+                    "ip": ip,
+                    "host_name": host_name,
+                    "duration": ms,
+                    "seq": idx+1
+                }));
             }
             Err(e) => {
                 error!("tcping {} failed: {}", ip.to_string(), e);
-                socket
-                    .emit(
-                        "tcping",
-                        json!({
-                            "ip": ip,
-                            "seq": idx+1,
-                            "error": SocketIOError::ErrTCPingFailed,
-                        }),
-                    )
-                    .unwrap();
+                job.emit(json!({
+                    "ip": ip,
+                    "host_name": host_name,
+                    "seq": idx+1,
+                    "error": SocketIOError::ErrTCPingFailed,
+                }));
             }
         };
     }
-    socket.disconnect().unwrap();
+    job.finish();
 }
 
-pub async fn dns(socket: SocketRef, data: Value) {
+pub async fn dns(job: Job, data: Value) {
     debug!("receive dns request: {}", data);
-    let domain = data["domain"].as_str().unwrap();
-    let type_ = data["type"].as_str().unwrap();
+    let (Some(domain), Some(type_)) = (data["domain"].as_str(), data["type"].as_str()) else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
     let ns = data["ns"].as_str();
+    let Some(source) = address_source(&job, &data) else {
+        return;
+    };
+    // queries to a given nameserver are bound here, to the default ones by resolve_bound
+    let ns_ip = ns.and_then(|ns| {
+        ns.parse::<SocketAddr>()
+            .map(|addr| addr.ip())
+            .or_else(|_| ns.parse::<IpAddr>())
+            .ok()
+    });
+    let bind_addr = match ns_ip {
+        Some(ns) if !source.is_default() => match source.local_ip(ns) {
+            Ok(ip) => Some(SocketAddr::new(ip, 0)),
+            Err(e) => {
+                error!("dns resolve {} failed: {}", domain, e);
+                job.emit(json!({
+                    "error": SocketIOError::ErrDNSLookupFailed
+                }));
+                return;
+            }
+        },
+        _ => None,
+    };
     let start = std::time::Instant::now();
-    let res = dns::resolve(domain, type_, ns).await;
+    let res = if ns.is_none() && !source.is_default() {
+        dns::resolve_bound(domain, type_, |ns| source.local_ip(ns)).await
+    } else {
+        dns::resolve_from(domain, type_, ns, bind_addr).await
+    };
     match res {
         Some(res) => {
             let ms = start.elapsed().as_millis();
@@ -213,48 +360,41 @@ pub async fn dns(socket: SocketRef, data: Value) {
                     .map(|ip| ip.to_string())
                     .collect::<Vec<String>>()
             };
-            socket
-                .emit(
-                    "dns",
-                    json!({
-                        "duration": ms,
-                        "ips": ips,
-                    }),
-                )
-                .unwrap();
+            job.emit(json!({
+                "duration": ms,
+                "ips": ips,
+            }));
         }
         None => {
-            socket
-                .emit(
-                    "dns",
-                    json!({
-                        "error": SocketIOError::ErrDNSLookupFailed
-                    }),
-                )
-                .unwrap();
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
         }
     }
 }
 
-pub async fn mtr(socket: SocketRef, data: Value) {
+pub async fn mtr(job: Job, data: Value) {
     debug!("receive mtr request: {}", data);
-    let host = data["host"].as_str().unwrap();
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
     let ns = data["ns"].as_str();
     let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
     let record_type = if is_ipv4 { "A" } else { "AAAA" };
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
     let ip = if is_ip(host) {
         host.to_string()
     } else {
         let res = dns::resolve(host, record_type, ns).await;
         if res.is_none() {
-            socket
-                .emit(
-                    "mtr",
-                    json!({
-                        "error": SocketIOError::ErrDNSLookupFailed
-                    }),
-                )
-                .unwrap();
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
             return;
         }
         res.unwrap()
@@ -264,149 +404,1483 @@ pub async fn mtr(socket: SocketRef, data: Value) {
             .unwrap()
             .to_string()
     };
-    let tracer: Tracer = Tracer::new(ip.parse().unwrap()).unwrap();
-    let rx = tracer.get_progress_receiver();
-    let handle = thread::spawn(move || tracer.trace());
-    let mut sent_hops = Vec::new();
-    while let Ok(msg) = rx.lock().unwrap().recv() {
-        sent_hops.push(msg.hop);
-        socket
-            .emit(
-                "mtr",
-                json!({
-                    "seq": msg.seq,
-                    "ip_addr": msg.ip_addr,
-                    "host_name": msg.host_name,
-                    "ttl": msg.ttl,
-                    "hop": msg.hop,
-                    "node_type": match msg.node_type {
-                        tracert::node::NodeType::DefaultGateway=> "DefaultGateway",
-                        tracert::node::NodeType::Relay=> "Relay",
-                        tracert::node::NodeType::Destination=> "Destination",
-                    },
-                    "rtt": msg.rtt.as_millis(),
-                }),
+    let flows = data["flows"]
+        .as_u64()
+        .unwrap_or(1)
+        .clamp(1, traceroute::MAX_FLOWS as u64) as u16;
+    let paris = data["paris"].as_bool().unwrap_or(false) || flows > 1;
+    let options = traceroute::Options {
+        first_hop: data["first_hop"].as_u64().unwrap_or(1) as u8,
+        max_hops: data["max_hops"].as_u64().unwrap_or(30).min(255) as u8,
+        timeout: Duration::from_millis(data["timeout"].as_u64().unwrap_or(1000)),
+        flow: if paris { Some(0) } else { None },
+    };
+    let target: IpAddr = ip.parse().unwrap();
+    let reverse = reverse(&data);
+    if flows > 1 {
+        mtr_paths(job, host, target, options, &source, flows, reverse.as_ref()).await;
+        return;
+    }
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let handle = tokio::task::spawn_blocking(move || {
+        traceroute::trace(target, &options, &source, |hop| tx.send(hop).is_ok())
+    });
+    while let Some(hop) = rx.recv().await {
+        if job.cancelled() {
+            return;
+        }
+        let host_name = match (&reverse, hop.addr) {
+            (Some(reverse), Some(addr)) => Some(
+                reverse
+                    .lookup(addr)
+                    .await
+                    .unwrap_or_else(|| addr.to_string()),
+            ),
+            _ => None,
+        };
+        job.emit(hop_json(&hop, host_name));
+    }
+    let res = match handle.await {
+        Ok(res) => res.map_err(|e| {
+            (
+                unsupported_or(&e, SocketIOError::ErrMTRFailed),
+                e.to_string(),
             )
-            .unwrap();
+        }),
+        Err(e) => Err((SocketIOError::ErrMTRFailed, e.to_string())),
+    };
+    if let Err((err, e)) = res {
+        error!("mtr {} failed: {}", host, e);
+        job.emit(json!({
+            "error": err
+        }));
     }
-    match handle.join().unwrap() {
-        Ok(r) => {
-            for node in r.nodes {
-                if sent_hops.contains(&node.hop) {
-                    continue;
+}
+
+/// Traces `flows` Paris flows at once and emits every distinct path with the flows taking it.
+async fn mtr_paths(
+    job: Job,
+    host: &str,
+    target: IpAddr,
+    options: traceroute::Options,
+    source: &Source,
+    flows: u16,
+    reverse: Option<&dns::Reverse>,
+) {
+    let handles: Vec<_> = (0..flows)
+        .map(|flow| {
+            let options = traceroute::Options {
+                flow: Some(flow),
+                ..options
+            };
+            let source = source.clone();
+            let cancelled = job.cancellation();
+            tokio::task::spawn_blocking(move || {
+                let mut hops = Vec::new();
+                traceroute::trace(target, &options, &source, |hop| {
+                    hops.push(hop);
+                    !cancelled.load(Ordering::Relaxed)
+                })
+                .map(|_| hops)
+            })
+        })
+        .collect();
+    // paths are told apart by the addresses of their hops, flows on the same path are grouped
+    let mut paths: Vec<(Vec<u16>, Vec<traceroute::Hop>)> = Vec::new();
+    for (flow, handle) in handles.into_iter().enumerate() {
+        let res = match handle.await {
+            Ok(res) => res.map_err(|e| {
+                (
+                    unsupported_or(&e, SocketIOError::ErrMTRFailed),
+                    e.to_string(),
+                )
+            }),
+            Err(e) => Err((SocketIOError::ErrMTRFailed, e.to_string())),
+        };
+        let hops = match res {
+            Ok(hops) => hops,
+            Err((err, e)) => {
+                error!("mtr {} failed: {}", host, e);
+                job.emit(json!({
+                    "error": err
+                }));
+                return;
+            }
+        };
+        match paths
+            .iter_mut()
+            .find(|path| traceroute::same_path(&path.1, &hops))
+        {
+            Some(path) => {
+                path.0.push(flow as u16);
+                // fill in the hops that didn't answer the flows grouped so far
+                for (known, hop) in path.1.iter_mut().zip(hops) {
+                    if known.addr.is_none() && hop.addr.is_some() {
+                        *known = hop;
+                    }
                 }
-                socket
-                    .emit(
-                        "mtr",
-                        json!({
-                            "seq": node.seq,
-                            "ip_addr": node.ip_addr,
-                            "host_name": node.host_name,
-                            "ttl": node.ttl,
-                            "hop": node.hop,
-                            "node_type": match node.node_type {
-                                tracert::node::NodeType::DefaultGateway=> "DefaultGateway",
-                                tracert::node::NodeType::Relay=> "Relay",
-                                tracert::node::NodeType::Destination=> "Destination",
-                            },
-                            "rtt": node.rtt.as_millis(),
-                        }),
-                    )
-                    .unwrap();
             }
+            None => paths.push((vec![flow as u16], hops)),
         }
-        Err(e) => {
-            error!("mtr {} failed: {}", host, e);
-            socket
-                .emit(
-                    "mtr",
-                    json!({
-                        "error": SocketIOError::ErrMTRFailed
-                    }),
-                )
-                .unwrap();
+    }
+    if job.cancelled() {
+        return;
+    }
+    let mut names = HashMap::new();
+    if let Some(reverse) = reverse {
+        let addrs = paths
+            .iter()
+            .flat_map(|path| path.1.iter().filter_map(|hop| hop.addr));
+        for addr in addrs {
+            if let Entry::Vacant(entry) = names.entry(addr) {
+                let name = reverse.lookup(addr).await;
+                entry.insert(name.unwrap_or_else(|| addr.to_string()));
+            }
         }
     }
+    for (idx, (flows, hops)) in paths.iter().enumerate() {
+        let hops: Vec<Value> = hops
+            .iter()
+            .map(|hop| hop_json(hop, hop.addr.and_then(|addr| names.get(&addr).cloned())))
+            .collect();
+        job.emit(json!({
+            "path": idx + 1,
+            "flows": flows,
+            "hops": hops,
+        }));
+    }
+}
+
+fn hop_json(hop: &traceroute::Hop, host_name: Option<String>) -> Value {
+    let node_type = if hop.reached {
+        "Destination"
+    } else if hop.hop == 1 {
+        "DefaultGateway"
+    } else {
+        "Relay"
+    };
+    let mpls: Vec<Value> = hop
+        .extensions
+        .mpls
+        .iter()
+        .map(|label| {
+            json!({
+                "label": label.label,
+                "tc": label.tc,
+                "s": label.bottom,
+                "ttl": label.ttl,
+            })
+        })
+        .collect();
+    let interfaces: Vec<Value> = hop
+        .extensions
+        .interfaces
+        .iter()
+        .map(|interface| {
+            json!({
+                "role": interface.role,
+                "ifindex": interface.ifindex,
+                "ip": interface.addr,
+                "name": interface.name,
+                "mtu": interface.mtu,
+            })
+        })
+        .collect();
+    json!({
+        "seq": hop.hop,
+        "ip_addr": hop.addr,
+        "host_name": host_name,
+        "ttl": hop.reply_ttl,
+        "hop": hop.hop,
+        "node_type": node_type,
+        "rtt": hop.rtt.map(|rtt| rtt.as_millis()),
+        "unreachable": hop.unreachable,
+        "mpls": mpls,
+        "interfaces": interfaces,
+    })
 }
 
-pub async fn http(socket: SocketRef, data: Value) {
+pub async fn http(job: Job, data: Value) {
     debug!("receive http request: {}", data);
-    let url = data["url"].as_str().unwrap();
     let ns = data["ns"].as_str();
     let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
-    let record_type = if is_ipv4 { "A" } else { "AAAA" };
-    let parsed_url = Url::parse(url).unwrap();
-    let host = parsed_url.host_str().unwrap();
-    let port = parsed_url.port().unwrap_or(80);
+    let (options, mut url) = match (
+        http::Options::parse(&data),
+        data["url"].as_str().and_then(|url| Url::parse(url).ok()),
+    ) {
+        (Some(options), Some(url)) => (options, url),
+        _ => {
+            job.emit(json!({
+                "error": SocketIOError::ErrInvalidRequest
+            }));
+            return;
+        }
+    };
+    let Some(source) = address_source(&job, &data) else {
+        return;
+    };
+    let reverse = reverse(&data);
     let start = std::time::Instant::now();
-    let ip = if is_ip(host) {
-        host.to_string()
-    } else {
-        let res = dns::resolve(host, record_type, ns).await;
-        if res.is_none() {
-            socket
-                .emit(
-                    "http",
+    let mut method = options.method.clone();
+    let mut body = options.body.clone();
+    let mut chain: Vec<Value> = Vec::new();
+    let mut first: Option<(IpAddr, u128)> = None;
+    let origin = url.host_str().unwrap_or_default().to_string();
+    loop {
+        let hop_start = std::time::Instant::now();
+        let host = url.host_str().unwrap_or_default().to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let is_origin = host == origin;
+        let ip = match options.resolve {
+            Some(ip) if is_origin => Some(ip),
+            _ => {
+                let name = host.trim_start_matches('[').trim_end_matches(']');
+                dns::resolve_ip(name, is_ipv4, ns).await
+            }
+        };
+        let ip = match ip {
+            Some(ip) => ip,
+            None => {
+                job.emit(json!({
+                    "duration": start.elapsed().as_millis(),
+                    "chain": chain,
+                    "error": SocketIOError::ErrDNSLookupFailed
+                }));
+                return;
+            }
+        };
+        let dns_duration = hop_start.elapsed().as_millis();
+        let (first_ip, first_dns_duration) = *first.get_or_insert((ip, dns_duration));
+        let hop = http::Hop {
+            url: &url,
+            method: &method,
+            body: body.as_ref(),
+            addr: SocketAddr::new(ip, port),
+            source: &source,
+            sni: options.sni.as_deref().filter(|_| is_origin),
+            timeout: options.timeout.saturating_sub(start.elapsed()),
+        };
+        let fetched = match http::fetch(&options, hop).await {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                error!("http {} failed: {}", url, e);
+                let mut result = json!({
+                    "duration": start.elapsed().as_millis(),
+                    "dns_duration": first_dns_duration,
+                    "ip": first_ip,
+                    "chain": chain,
+                    "error": SocketIOError::ErrHTTPFailed,
+                });
+                add_host_names(reverse.as_ref(), &mut result).await;
+                job.emit(result);
+                return;
+            }
+        };
+        let status = fetched.status;
+        let version = format!("{:?}", fetched.version);
+        chain.push(json!({
+            "url": url.as_str(),
+            "ip": ip,
+            "status": status.as_u16(),
+            "version": version,
+            "dns_duration": dns_duration,
+            "handshake_duration": fetched.handshake.map(|d| d.as_millis()),
+            "duration": hop_start.elapsed().as_millis(),
+        }));
+        if let Some(expected) = options.version.map(|v| v.expected()) {
+            if fetched.version != expected {
+                error!(
+                    "http {} negotiated {} instead of {:?}",
+                    url, version, expected
+                );
+                let mut result = json!({
+                    "duration": start.elapsed().as_millis(),
+                    "dns_duration": first_dns_duration,
+                    "ip": first_ip,
+                    "chain": chain,
+                    "version": version,
+                    "error": SocketIOError::ErrHTTPVersionMismatch,
+                });
+                add_host_names(reverse.as_ref(), &mut result).await;
+                job.emit(result);
+                return;
+            }
+        }
+        let location = fetched
+            .headers
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok());
+        match location {
+            Some(next) if options.follow_redirects && status.is_redirection() => {
+                if chain.len() > http::MAX_REDIRECTS {
+                    error!("http {} failed: too many redirects", url);
+                    let mut result = json!({
+                        "duration": start.elapsed().as_millis(),
+                        "dns_duration": first_dns_duration,
+                        "ip": first_ip,
+                        "chain": chain,
+                        "error": SocketIOError::ErrHTTPFailed,
+                    });
+                    add_host_names(reverse.as_ref(), &mut result).await;
+                    job.emit(result);
+                    return;
+                }
+                let next_method = http::redirect_method(status, &method);
+                if next_method != method {
+                    body = None;
+                }
+                method = next_method;
+                url = next;
+            }
+            _ => {
+                let assertions: Vec<Value> = options
+                    .assertions
+                    .iter()
+                    .map(|assertion| assertion.evaluate(status, &fetched.headers, &fetched.body))
+                    .collect();
+                let passed = assertions.iter().all(|a| a["passed"] == json!(true));
+                let mut result = json!({
+                    "duration": start.elapsed().as_millis(),
+                    "ip": first_ip,
+                    "dns_duration": first_dns_duration,
+                    "status": status.as_u16(),
+                    "version": version,
+                    "handshake_duration": fetched.handshake.map(|d| d.as_millis()),
+                    "alt_svc_h3": fetched.alt_svc_h3(),
+                    "url": url.as_str(),
+                    "chain": chain,
+                    "body_bytes": fetched.body.len(),
+                    "truncated": fetched.truncated,
+                    "assertions": assertions,
+                    "passed": passed,
+                });
+                add_host_names(reverse.as_ref(), &mut result).await;
+                job.emit(result);
+                return;
+            }
+        }
+    }
+}
+
+pub async fn udp(job: Job, data: Value) {
+    debug!("receive udp request: {}", data);
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let single = data["single"].as_bool().unwrap_or(true);
+    let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
+    let ns = data["ns"].as_str();
+    let encoding = data["encoding"].as_str().unwrap_or("hex");
+    let timeout = Duration::from_millis(data["timeout"].as_u64().unwrap_or(1000));
+    let template = data["template"].as_str().map(udp::Template::parse);
+    let payload = data["payload"]
+        .as_str()
+        .map(|payload| udp::decode(payload, encoding));
+    let expect = data["expect"]
+        .as_str()
+        .map(|expect| udp::decode(expect, encoding));
+    let (template, payload, expect) = match (template, payload, expect) {
+        (Some(None), _, _) | (_, Some(None), _) | (_, _, Some(None)) => {
+            job.emit(json!({
+                "error": SocketIOError::ErrInvalidRequest
+            }));
+            return;
+        }
+        (template, payload, expect) => (template.flatten(), payload.flatten(), expect.flatten()),
+    };
+    let port = match template {
+        Some(template) => request_port(&data, template.port()),
+        None => data["port"]
+            .as_u64()
+            .and_then(|port| u16::try_from(port).ok()),
+    };
+    // a payload is sent as is, a template makes fresh ids for every probe and tells the answers
+    // to them apart from late ones
+    let (port, mut request, template) = match (port, payload, template) {
+        (Some(port), Some(payload), _) => (port, payload, None),
+        (Some(port), None, Some(template)) => (port, template.payload(), Some(template)),
+        _ => {
+            job.emit(json!({
+                "error": SocketIOError::ErrInvalidRequest
+            }));
+            return;
+        }
+    };
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
+    let ip = match dns::resolve_ip(host, is_ipv4, ns).await {
+        Some(ip) => ip,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
+            return;
+        }
+    };
+    let sock = match sockets::udp_connect(&source, SocketAddr::new(ip, port)).await {
+        Ok(sock) => sock,
+        Err(e) => {
+            error!("udp {} failed: {}", host, e);
+            job.emit(json!({
+                "ip": ip,
+                "error": SocketIOError::ErrUDPFailed
+            }));
+            return;
+        }
+    };
+    let times = if single { 1 } else { 100 };
+    let mut interval = time::interval(Duration::from_secs(1));
+    let mut buf = vec![0u8; 65535];
+    for idx in 0..times {
+        if job.cancelled() {
+            return;
+        }
+        interval.tick().await;
+        if let Some(template) = template.filter(|_| idx > 0) {
+            request = template.payload();
+        }
+        let start = std::time::Instant::now();
+        let res = time::timeout(timeout, async {
+            sock.send(&request).await?;
+            loop {
+                let n = sock.recv(&mut buf).await?;
+                // skip late answers to previous probes when the template can tell them apart
+                match template {
+                    Some(template) if !template.matches(&request, &buf[..n]) => continue,
+                    _ => return Ok::<usize, std::io::Error>(n),
+                }
+            }
+        })
+        .await;
+        match res {
+            Ok(Ok(n)) => {
+                let response = &buf[..n];
+                let matched = expect.as_ref().map(|e| udp::contains(response, e));
+                let mut result = json!({
+                    "ip": ip,
+                    "duration": start.elapsed().as_secs_f64() * 1000.0,
+                    "seq": idx+1,
+                    "size": n,
+                    "matched": matched,
+                    "response": hex::encode(&response[..n.min(512)]),
+                });
+                if matched == Some(false) {
+                    result["error"] = json!(SocketIOError::ErrUDPResponseMismatch);
+                }
+                job.emit(result);
+            }
+            Ok(Err(e)) => {
+                error!("udp {} failed: {}", host, e);
+                job.emit(json!({
+                    "ip": ip,
+                    "seq": idx+1,
+                    "error": SocketIOError::ErrUDPFailed,
+                }));
+            }
+            Err(_) => {
+                job.emit(json!({
+                    "ip": ip,
+                    "seq": idx+1,
+                    "error": SocketIOError::ErrUDPTimeout,
+                }));
+            }
+        }
+    }
+    job.finish();
+}
+
+pub async fn ntp(job: Job, data: Value) {
+    debug!("receive ntp request: {}", data);
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let single = data["single"].as_bool().unwrap_or(true);
+    let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
+    let ns = data["ns"].as_str();
+    let Some(port) = request_port(&data, ntp::PORT) else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let timeout = Duration::from_millis(data["timeout"].as_u64().unwrap_or(1000));
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
+    let ip = match dns::resolve_ip(host, is_ipv4, ns).await {
+        Some(ip) => ip,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
+            return;
+        }
+    };
+    let sock = match sockets::udp_connect(&source, SocketAddr::new(ip, port)).await {
+        Ok(sock) => sock,
+        Err(e) => {
+            error!("ntp {} failed: {}", host, e);
+            job.emit(json!({
+                "ip": ip,
+                "error": SocketIOError::ErrNTPFailed
+            }));
+            return;
+        }
+    };
+    let times = if single { 1 } else { 100 };
+    let mut interval = time::interval(Duration::from_secs(1));
+    let mut buf = [0u8; 1024];
+    for idx in 0..times {
+        if job.cancelled() {
+            return;
+        }
+        interval.tick().await;
+        let start = std::time::Instant::now();
+        let sent = SystemTime::now();
+        let request = ntp::request(sent);
+        let res = time::timeout(timeout, async {
+            sock.send(&request).await?;
+            loop {
+                let n = sock.recv(&mut buf).await?;
+                if let Some(res) = ntp::parse(&request, &buf[..n], sent, SystemTime::now()) {
+                    return Ok::<ntp::Response, std::io::Error>(res);
+                }
+            }
+        })
+        .await;
+        match res {
+            Ok(Ok(res)) => {
+                let mut result = json!({
+                    "ip": ip,
+                    "duration": start.elapsed().as_millis(),
+                    "seq": idx+1,
+                    "leap": res.leap,
+                    "version": res.version,
+                    "stratum": res.stratum,
+                    "poll": res.poll,
+                    "precision": res.precision,
+                    "reference_id": res.reference_id,
+                    "reference_time": res.reference_time,
+                    "root_delay": res.root_delay * 1000.0,
+                    "root_dispersion": res.root_dispersion * 1000.0,
+                    "delay": res.delay * 1000.0,
+                    "offset": res.offset * 1000.0,
+                });
+                // stratum 0 is a kiss-o'-death packet, the reference id holds the kiss code
+                if res.stratum == 0 {
+                    result["error"] = json!(SocketIOError::ErrNTPKissOfDeath);
+                }
+                job.emit(result);
+            }
+            Ok(Err(e)) => {
+                error!("ntp {} failed: {}", host, e);
+                job.emit(json!({
+                    "ip": ip,
+                    "seq": idx+1,
+                    "error": SocketIOError::ErrNTPFailed,
+                }));
+            }
+            Err(_) => {
+                job.emit(json!({
+                    "ip": ip,
+                    "seq": idx+1,
+                    "error": SocketIOError::ErrNTPTimeout,
+                }));
+            }
+        }
+    }
+    job.finish();
+}
+
+pub async fn pmtu(job: Job, data: Value) {
+    debug!("receive pmtu request: {}", data);
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
+    let ns = data["ns"].as_str();
+    let Some(port) = request_port(&data, pmtu::PORT) else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let timeout = Duration::from_millis(data["timeout"].as_u64().unwrap_or(1000));
+    let mode = match data["mode"].as_str() {
+        Some("linear") => pmtu::Mode::Linear(data["step"].as_u64().unwrap_or(32) as u32),
+        _ => pmtu::Mode::Binary,
+    };
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
+    let ip = match dns::resolve_ip(host, is_ipv4, ns).await {
+        Some(ip) => ip,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
+            return;
+        }
+    };
+    let start = std::time::Instant::now();
+    let target = SocketAddr::new(ip, port);
+    let res =
+        tokio::task::spawn_blocking(move || pmtu::discover(target, &source, mode, timeout)).await;
+    match res {
+        Ok(Ok(report)) => {
+            let probes: Vec<Value> = report
+                .probes
+                .iter()
+                .map(|(size, outcome)| {
                     json!({
-                        "error": SocketIOError::ErrDNSLookupFailed
-                    }),
-                )
-                .unwrap();
+                        "size": size,
+                        "result": outcome.name(),
+                    })
+                })
+                .collect();
+            job.emit(json!({
+                "ip": ip,
+                "duration": start.elapsed().as_millis(),
+                "mtu": report.mtu,
+                "local_mtu": report.local_mtu,
+                "hop": report.hop,
+                "probes": probes,
+            }));
+        }
+        Ok(Err(e)) => {
+            error!("pmtu {} failed: {}", host, e);
+            job.emit(json!({
+                "ip": ip,
+                "error": unsupported_or(&e, SocketIOError::ErrPMTUFailed)
+            }));
+        }
+        Err(e) => {
+            error!("pmtu {} failed: {}", host, e);
+            job.emit(json!({
+                "ip": ip,
+                "error": SocketIOError::ErrPMTUFailed
+            }));
+        }
+    }
+}
+
+pub async fn websocket(job: Job, data: Value) {
+    debug!("receive websocket request: {}", data);
+    let ns = data["ns"].as_str();
+    let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
+    let timeout = Duration::from_millis(data["timeout"].as_u64().unwrap_or(5000));
+    let url = data["url"]
+        .as_str()
+        .and_then(|url| Url::parse(url).ok())
+        .filter(|url| url.scheme() == "ws" || url.scheme() == "wss");
+    let request = url.as_ref().and_then(|url| {
+        let mut request = url.as_str().into_client_request().ok()?;
+        if let Some(headers) = data["headers"].as_object() {
+            for (name, value) in headers {
+                request.headers_mut().insert(
+                    WsHeaderName::from_bytes(name.as_bytes()).ok()?,
+                    WsHeaderValue::from_str(value.as_str()?).ok()?,
+                );
+            }
+        }
+        Some(request)
+    });
+    let (url, request) = match (url, request) {
+        (Some(url), Some(request)) => (url, request),
+        _ => {
+            job.emit(json!({
+                "error": SocketIOError::ErrInvalidRequest
+            }));
+            return;
+        }
+    };
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
+    let start = std::time::Instant::now();
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let ip = match dns::resolve_ip(host, is_ipv4, ns).await {
+        Some(ip) => ip,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
             return;
         }
-        res.unwrap()
-            .iter()
-            .filter(|ip| is_ip(&ip.to_string()))
-            .next()
-            .unwrap()
-            .to_string()
     };
     let dns_duration = start.elapsed().as_millis();
-    let client: reqwest::Client;
-    if is_ipv4 {
-        let localhost_v4 = IpAddr::V4("0.0.0.0".parse().unwrap());
-        client = reqwest::Client::builder()
-            .local_address(localhost_v4)
-            .resolve(host, SocketAddr::new(ip.parse().unwrap(), port))
-            .build()
-            .unwrap();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let connect_start = std::time::Instant::now();
+    let stream = match time::timeout(
+        timeout,
+        sockets::tcp_connect(&source, SocketAddr::new(ip, port)),
+    )
+    .await
+    {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            error!("websocket {} failed: {}", url, e);
+            job.emit(json!({
+                "ip": ip,
+                "dns_duration": dns_duration,
+                "error": SocketIOError::ErrWebSocketFailed,
+            }));
+            return;
+        }
+        Err(_) => {
+            job.emit(json!({
+                "ip": ip,
+                "dns_duration": dns_duration,
+                "error": SocketIOError::ErrWebSocketTimeout,
+            }));
+            return;
+        }
+    };
+    let connect_duration = connect_start.elapsed().as_millis();
+    let handshake_start = std::time::Instant::now();
+    let handshake = time::timeout(
+        timeout,
+        client_async_tls_with_config(request, stream, None, None),
+    )
+    .await;
+    let (mut ws, response) = match handshake {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => {
+            error!("websocket {} failed: {}", url, e);
+            // the server answered the upgrade with a plain http response
+            let status = match &e {
+                WsError::Http(response) => Some(response.status().as_u16()),
+                _ => None,
+            };
+            job.emit(json!({
+                "ip": ip,
+                "dns_duration": dns_duration,
+                "connect_duration": connect_duration,
+                "status": status,
+                "error": SocketIOError::ErrWebSocketFailed,
+            }));
+            return;
+        }
+        Err(_) => {
+            job.emit(json!({
+                "ip": ip,
+                "dns_duration": dns_duration,
+                "connect_duration": connect_duration,
+                "error": SocketIOError::ErrWebSocketTimeout,
+            }));
+            return;
+        }
+    };
+    let mut result = json!({
+        "ip": ip,
+        "dns_duration": dns_duration,
+        "connect_duration": connect_duration,
+        "handshake_duration": handshake_start.elapsed().as_millis(),
+        "status": response.status().as_u16(),
+        "protocol": response
+            .headers()
+            .get("sec-websocket-protocol")
+            .and_then(|value| value.to_str().ok()),
+    });
+    if let Some(message) = data["message"].as_str() {
+        let sent = std::time::Instant::now();
+        let reply = time::timeout(timeout, async {
+            ws.send(Message::Text(message.to_string())).await?;
+            while let Some(msg) = ws.next().await {
+                match msg? {
+                    Message::Text(text) => return Ok(Some(text.len())),
+                    Message::Binary(bin) => return Ok(Some(bin.len())),
+                    Message::Close(_) => return Ok(None),
+                    _ => {}
+                }
+            }
+            Ok::<Option<usize>, WsError>(None)
+        })
+        .await;
+        match reply {
+            Ok(Ok(Some(size))) => {
+                result["reply_duration"] = json!(sent.elapsed().as_millis());
+                result["reply_bytes"] = json!(size);
+            }
+            Ok(Ok(None)) => {
+                error!("websocket {} closed before replying", url);
+                result["error"] = json!(SocketIOError::ErrWebSocketFailed);
+            }
+            Ok(Err(e)) => {
+                error!("websocket {} failed: {}", url, e);
+                result["error"] = json!(SocketIOError::ErrWebSocketFailed);
+            }
+            Err(_) => result["error"] = json!(SocketIOError::ErrWebSocketTimeout),
+        }
+    }
+    let close = time::timeout(timeout, async {
+        ws.close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: "".into(),
+        }))
+        .await?;
+        while let Some(msg) = ws.next().await {
+            if let Message::Close(frame) = msg? {
+                return Ok(frame.map(|frame| u16::from(frame.code)));
+            }
+        }
+        Ok::<Option<u16>, WsError>(None)
+    })
+    .await;
+    match close {
+        Ok(Ok(code)) => {
+            result["close_code"] = json!(code);
+            result["closed_cleanly"] = json!(true);
+        }
+        _ => result["closed_cleanly"] = json!(false),
+    }
+    result["duration"] = json!(start.elapsed().as_millis());
+    job.emit(result);
+}
+
+fn metadata_json(metadata: &MetadataMap) -> Value {
+    let headers = metadata.clone().into_headers();
+    let map: serde_json::Map<String, Value> = headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), json!(value.to_str().ok()?))))
+        .collect();
+    Value::Object(map)
+}
+
+pub async fn grpc(job: Job, data: Value) {
+    debug!("receive grpc request: {}", data);
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
+    let ns = data["ns"].as_str();
+    let tls = data["tls"].as_bool().unwrap_or(true);
+    let Some(port) = request_port(&data, if tls { 443 } else { 80 }) else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let service = data["service"].as_str().unwrap_or_default().to_string();
+    let timeout = Duration::from_millis(data["timeout"].as_u64().unwrap_or(5000));
+    let start = std::time::Instant::now();
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
+    let ip = match dns::resolve_ip(host, is_ipv4, ns).await {
+        Some(ip) => ip,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
+            return;
+        }
+    };
+    let dns_duration = start.elapsed().as_millis();
+    let scheme = if tls { "https" } else { "http" };
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    };
+    // connect to the resolved address, the origin keeps the host for :authority and tls
+    let endpoint = format!("{}://{}", scheme, authority)
+        .parse::<Uri>()
+        .ok()
+        .and_then(|origin| {
+            let endpoint =
+                Endpoint::from_shared(format!("{}://{}", scheme, SocketAddr::new(ip, port)))
+                    .ok()?
+                    .origin(origin)
+                    .timeout(timeout)
+                    .connect_timeout(timeout);
+            if !tls {
+                return Some(endpoint);
+            }
+            endpoint
+                .tls_config(ClientTlsConfig::new().domain_name(host).with_webpki_roots())
+                .ok()
+        });
+    let endpoint = match endpoint {
+        Some(endpoint) => endpoint,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrInvalidRequest
+            }));
+            return;
+        }
+    };
+    let connect_start = std::time::Instant::now();
+    let connected = if source.is_default() {
+        endpoint.connect().await
     } else {
-        let localhost_v6 = IpAddr::V6("::".parse().unwrap());
-        client = reqwest::Client::builder()
-            .local_address(localhost_v6)
-            .resolve(host, SocketAddr::new(ip.parse().unwrap(), port))
-            .build()
-            .unwrap();
-    }
-    let res = client.get(url).send().await;
+        // tonic still puts tls over the stream of the connector
+        let addr = SocketAddr::new(ip, port);
+        let connector = service_fn(move |_: Uri| {
+            let source = source.clone();
+            async move { sockets::tcp_connect(&source, addr).await.map(TokioIo::new) }
+        });
+        endpoint.connect_with_connector(connector).await
+    };
+    let channel = match connected {
+        Ok(channel) => channel,
+        Err(e) => {
+            error!("grpc {} failed: {}", host, e);
+            job.emit(json!({
+                "ip": ip,
+                "dns_duration": dns_duration,
+                "error": SocketIOError::ErrGRPCFailed,
+            }));
+            return;
+        }
+    };
+    let connect_duration = connect_start.elapsed().as_millis();
+    let check_start = std::time::Instant::now();
+    let res = HealthClient::new(channel)
+        .check(HealthCheckRequest { service })
+        .await;
     match res {
         Ok(res) => {
-            let status = res.status().as_u16();
-            socket
-                .emit(
-                    "http",
-                    json!({
-                        "duration": start.elapsed().as_millis(),
-                        "ip": ip,
-                        "dns_duration": dns_duration,
-                        "status": status,
-                    }),
-                )
-                .unwrap();
+            let (metadata, res, _) = res.into_parts();
+            let status = ServingStatus::try_from(res.status).unwrap_or(ServingStatus::Unknown);
+            let mut result = json!({
+                "ip": ip,
+                "duration": start.elapsed().as_millis(),
+                "dns_duration": dns_duration,
+                "connect_duration": connect_duration,
+                "check_duration": check_start.elapsed().as_millis(),
+                "serving_status": status.as_str_name(),
+                "grpc_status": tonic::Code::Ok as i32,
+                "metadata": metadata_json(&metadata),
+            });
+            if status != ServingStatus::Serving {
+                result["error"] = json!(SocketIOError::ErrGRPCNotServing);
+            }
+            job.emit(result);
+        }
+        Err(status) => {
+            error!("grpc {} failed: {}", host, status);
+            job.emit(json!({
+                "ip": ip,
+                "duration": start.elapsed().as_millis(),
+                "dns_duration": dns_duration,
+                "connect_duration": connect_duration,
+                "grpc_status": status.code() as i32,
+                "grpc_message": status.message(),
+                "metadata": metadata_json(status.metadata()),
+                "error": SocketIOError::ErrGRPCFailed,
+            }));
+        }
+    }
+}
+
+pub async fn service(job: Job, data: Value) {
+    debug!("receive service request: {}", data);
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
+    let ns = data["ns"].as_str();
+    let timeout = Duration::from_millis(data["timeout"].as_u64().unwrap_or(5000));
+    let protocol = match data["protocol"].as_str().and_then(service::Protocol::parse) {
+        Some(protocol) => protocol,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrInvalidRequest
+            }));
+            return;
+        }
+    };
+    let Some(port) = request_port(&data, protocol.port()) else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let options = service::Options {
+        ehlo: data["ehlo"].as_str().unwrap_or("nodecook-agent"),
+        starttls: data["starttls"].as_bool().unwrap_or(false),
+        tls: data["tls"].as_bool().unwrap_or(false),
+        insecure: data["insecure"].as_bool().unwrap_or(false),
+        server_name: data["sni"].as_str().unwrap_or(host),
+    };
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
+    let ip = match dns::resolve_ip(host, is_ipv4, ns).await {
+        Some(ip) => ip,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
+            return;
+        }
+    };
+    let start = std::time::Instant::now();
+    let res = time::timeout(timeout, async {
+        let stream = sockets::tcp_connect(&source, SocketAddr::new(ip, port)).await?;
+        let connect_duration = start.elapsed();
+        let report = service::check(stream, protocol, &options).await?;
+        Ok::<_, service::Failure>((connect_duration, report))
+    })
+    .await;
+    match res {
+        Ok(Ok((connect_duration, report))) => {
+            let tls = report.tls.map(|tls| {
+                json!({
+                    "version": tls.version,
+                    "cipher": tls.cipher,
+                    "duration": tls.duration.as_millis(),
+                    "certificate": tls.certificate.map(|cert| json!({
+                        "subject": cert.subject,
+                        "issuer": cert.issuer,
+                        "serial": cert.serial,
+                        "not_before": cert.not_before,
+                        "not_after": cert.not_after,
+                        "dns_names": cert.dns_names,
+                    })),
+                })
+            });
+            job.emit(json!({
+                "ip": ip,
+                "duration": start.elapsed().as_millis(),
+                "connect_duration": connect_duration.as_millis(),
+                "greeting_duration": report.greeting_duration.as_millis(),
+                "banner": report.banner,
+                "version": report.version,
+                "code": report.code,
+                "extensions": report.extensions,
+                "tls_supported": report.tls_supported,
+                "tls": tls,
+            }));
+        }
+        Ok(Err(e)) => {
+            error!("service {} failed: {}", host, e);
+            let error = match e {
+                service::Failure::Io(_) => SocketIOError::ErrServiceFailed,
+                service::Failure::BadGreeting(_) => SocketIOError::ErrServiceBadGreeting,
+                service::Failure::StartTls(_) | service::Failure::Tls(_) => {
+                    SocketIOError::ErrStartTLSFailed
+                }
+            };
+            job.emit(json!({
+                "ip": ip,
+                "duration": start.elapsed().as_millis(),
+                "error": error,
+            }));
+        }
+        Err(_) => {
+            job.emit(json!({
+                "ip": ip,
+                "duration": start.elapsed().as_millis(),
+                "error": SocketIOError::ErrServiceTimeout,
+            }));
+        }
+    }
+}
+
+pub async fn bandwidth(job: Job, data: Value) {
+    debug!("receive bandwidth request: {}", data);
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
+    let ns = data["ns"].as_str();
+    let Some(port) = request_port(&data, bandwidth::PORT) else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let mode = match data["mode"].as_str().unwrap_or("tcp") {
+        "tcp" => Some(bandwidth::Mode::Tcp),
+        "udp" => Some(bandwidth::Mode::Udp),
+        _ => None,
+    };
+    let direction = match data["direction"].as_str().unwrap_or("upload") {
+        "upload" => Some(bandwidth::Direction::Upload),
+        "download" => Some(bandwidth::Direction::Download),
+        _ => None,
+    };
+    let (key, mode, direction) = match (data["key"].as_str(), mode, direction) {
+        (Some(key), Some(mode), Some(direction)) => (key, mode, direction),
+        _ => {
+            job.emit(json!({
+                "error": SocketIOError::ErrInvalidRequest
+            }));
+            return;
+        }
+    };
+    let params = bandwidth::Params {
+        mode,
+        direction,
+        duration: data["duration"].as_u64().unwrap_or(10),
+        streams: data["streams"].as_u64().unwrap_or(1) as usize,
+        bitrate: data["bitrate"].as_u64().unwrap_or(10_000_000),
+        size: data["size"].as_u64().unwrap_or(1200) as usize,
+    };
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
+    let ip = match dns::resolve_ip(host, is_ipv4, ns).await {
+        Some(ip) => ip,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
+            return;
+        }
+    };
+    let start = std::time::Instant::now();
+    match bandwidth::run(SocketAddr::new(ip, port), &source, key, params).await {
+        Ok(stats) => {
+            let mut result = stats.to_json();
+            result["ip"] = json!(ip);
+            result["mode"] = json!(mode);
+            result["direction"] = json!(direction);
+            result["elapsed"] = json!(start.elapsed().as_millis());
+            job.emit(result);
         }
         Err(e) => {
-            error!("http {} failed: {}", url, e);
-            socket
-                .emit(
-                    "http",
-                    json!({
-                        "duration": start.elapsed().as_millis(),
-                        "dns_duration": dns_duration,
-                        "ip": ip,
-                        "error": SocketIOError::ErrHTTPFailed,
-                    }),
-                )
-                .unwrap();
+            error!("bandwidth {} failed: {}", host, e);
+            job.emit(json!({
+                "ip": ip,
+                "error": SocketIOError::ErrBandwidthFailed,
+            }));
+        }
+    }
+}
+
+pub async fn download(job: Job, data: Value) {
+    debug!("receive download request: {}", data);
+    let ns = data["ns"].as_str();
+    let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
+    let insecure = data["insecure"].as_bool().unwrap_or(false);
+    let max_bytes = data["max_bytes"].as_u64().unwrap_or(100 << 20);
+    let max_time = Duration::from_millis(data["max_time"].as_u64().unwrap_or(30_000));
+    let every = Duration::from_millis(data["interval"].as_u64().unwrap_or(1000).max(100));
+    let expected = data["sha256"].as_str().map(|hash| hash.to_lowercase());
+    let url = match data["url"].as_str().and_then(|url| Url::parse(url).ok()) {
+        Some(url) => url,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrInvalidRequest
+            }));
+            return;
+        }
+    };
+    let Some(source) = address_source(&job, &data) else {
+        return;
+    };
+    let host = url.host_str().unwrap_or_default();
+    let start = std::time::Instant::now();
+    let ip = match dns::resolve_ip(
+        host.trim_start_matches('[').trim_end_matches(']'),
+        is_ipv4,
+        ns,
+    )
+    .await
+    {
+        Some(ip) => ip,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
+            return;
+        }
+    };
+    let dns_duration = start.elapsed().as_millis();
+    let local = match source.local_ip(ip) {
+        Ok(local) => local,
+        Err(e) => {
+            error!("download {} failed: {}", url, e);
+            job.emit(json!({
+                "ip": ip,
+                "dns_duration": dns_duration,
+                "error": SocketIOError::ErrDownloadFailed,
+            }));
+            return;
+        }
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    let client = reqwest::Client::builder()
+        .local_address(local)
+        .resolve(host, SocketAddr::new(ip, port))
+        .danger_accept_invalid_certs(insecure)
+        .build()
+        .unwrap();
+    let mut res = match time::timeout(max_time, client.get(url.clone()).send()).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => {
+            error!("download {} failed: {}", url, e);
+            job.emit(json!({
+                "ip": ip,
+                "dns_duration": dns_duration,
+                "error": SocketIOError::ErrDownloadFailed,
+            }));
+            return;
+        }
+        Err(_) => {
+            error!("download {} failed: no response in time", url);
+            job.emit(json!({
+                "ip": ip,
+                "dns_duration": dns_duration,
+                "error": SocketIOError::ErrDownloadFailed,
+            }));
+            return;
+        }
+    };
+    let ttfb = start.elapsed().as_millis();
+    let status = res.status().as_u16();
+    let content_length = res.content_length();
+    let body_start = std::time::Instant::now();
+    let deadline = time::Instant::now() + max_time.saturating_sub(start.elapsed());
+    let mut hasher = Sha256::new();
+    let mut bytes: u64 = 0;
+    let mut last = (body_start, 0u64);
+    let mut complete = false;
+    let mut truncated = false;
+    let mut failed = false;
+    // a body of exactly max_bytes is only complete once the end of the stream is seen
+    loop {
+        if job.cancelled() {
+            return;
+        }
+        let chunk = match time::timeout_at(deadline, res.chunk()).await {
+            Ok(Ok(Some(chunk))) => chunk,
+            Ok(Ok(None)) => {
+                complete = true;
+                break;
+            }
+            Ok(Err(e)) => {
+                error!("download {} failed: {}", url, e);
+                failed = true;
+                break;
+            }
+            Err(_) => break,
+        };
+        let room = (max_bytes - bytes) as usize;
+        truncated = chunk.len() > room;
+        let chunk = &chunk[..chunk.len().min(room)];
+        hasher.update(chunk);
+        bytes += chunk.len() as u64;
+        if last.0.elapsed() >= every {
+            let elapsed = body_start.elapsed().as_secs_f64();
+            job.emit(json!({
+                "progress": true,
+                "ip": ip,
+                "bytes": bytes,
+                "content_length": content_length,
+                "elapsed": body_start.elapsed().as_millis(),
+                "speed": (bytes - last.1) as f64 / last.0.elapsed().as_secs_f64(),
+                "average_speed": bytes as f64 / elapsed,
+            }));
+            last = (std::time::Instant::now(), bytes);
         }
+        if truncated {
+            break;
+        }
+    }
+    let elapsed = body_start.elapsed().as_secs_f64();
+    // the hash only means something for the whole body
+    let sha256 = if complete {
+        Some(hex::encode(hasher.finalize()))
+    } else {
+        None
+    };
+    let matched = match (&expected, &sha256) {
+        (Some(expected), Some(sha256)) => Some(expected == sha256),
+        _ => None,
+    };
+    let mut result = json!({
+        "progress": false,
+        "ip": ip,
+        "status": status,
+        "duration": start.elapsed().as_millis(),
+        "dns_duration": dns_duration,
+        "ttfb": ttfb,
+        "bytes": bytes,
+        "content_length": content_length,
+        "complete": complete,
+        "truncated": truncated,
+        "speed": if elapsed > 0.0 { bytes as f64 / elapsed } else { 0.0 },
+        "sha256": sha256,
+        "sha256_matched": matched,
+    });
+    if failed {
+        result["error"] = json!(SocketIOError::ErrDownloadFailed);
+    } else if expected.is_some() && !complete {
+        // cut by max_bytes or max_time, there is no whole body to check the hash of
+        result["error"] = json!(SocketIOError::ErrDownloadIncomplete);
+    } else if matched == Some(false) {
+        result["error"] = json!(SocketIOError::ErrDownloadHashMismatch);
+    }
+    job.emit(result);
+}
+
+pub async fn twamp(job: Job, data: Value) {
+    debug!("receive twamp request: {}", data);
+    let Some(host) = data["host"].as_str() else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let single = data["single"].as_bool().unwrap_or(true);
+    let is_ipv4 = data["is_ipv4"].as_bool().unwrap_or(true);
+    let ns = data["ns"].as_str();
+    let Some(port) = request_port(&data, twamp::PORT) else {
+        job.emit(json!({
+            "error": SocketIOError::ErrInvalidRequest
+        }));
+        return;
+    };
+    let size = data["size"].as_u64().unwrap_or(50) as usize;
+    let timeout = Duration::from_millis(data["timeout"].as_u64().unwrap_or(1000));
+    let Some(source) = source(&job, &data) else {
+        return;
+    };
+    let ip = match dns::resolve_ip(host, is_ipv4, ns).await {
+        Some(ip) => ip,
+        None => {
+            job.emit(json!({
+                "error": SocketIOError::ErrDNSLookupFailed
+            }));
+            return;
+        }
+    };
+    let sock = match sockets::udp_connect(&source, SocketAddr::new(ip, port)).await {
+        Ok(sock) => sock,
+        Err(e) => {
+            error!("twamp {} failed: {}", host, e);
+            job.emit(json!({
+                "ip": ip,
+                "error": SocketIOError::ErrTWAMPFailed
+            }));
+            return;
+        }
+    };
+    let times: u32 = if single { 1 } else { 100 };
+    let mut interval = time::interval(Duration::from_secs(1));
+    let mut buf = [0u8; twamp::MAX_SIZE];
+    let mut sent = 0u32;
+    let mut last_sent = std::time::Instant::now();
+    let mut seen = std::collections::HashSet::new();
+    let mut max_seq: Option<u32> = None;
+    let mut duplicates = 0u32;
+    let mut reordered = 0u32;
+    let mut refused = false;
+    let mut rtts = Vec::new();
+    let mut rtt_jitter = twamp::Jitter::default();
+    let mut forward_jitter = twamp::Jitter::default();
+    let mut backward_jitter = twamp::Jitter::default();
+    // packets are sent at a fixed pace without waiting for replies, so reordering shows up
+    while sent < times || (seen.len() < times as usize && last_sent.elapsed() < timeout) {
+        if job.cancelled() {
+            return;
+        }
+        tokio::select! {
+            _ = interval.tick(), if sent < times => {
+                let packet = twamp::request(sent, SystemTime::now(), size);
+                if let Err(e) = sock.send(&packet).await {
+                    debug!("twamp send to {} failed: {}", host, e);
+                }
+                sent += 1;
+                last_sent = std::time::Instant::now();
+            }
+            res = sock.recv(&mut buf) => {
+                let n = match res {
+                    Ok(n) => n,
+                    Err(e) => {
+                        debug!("twamp receive from {} failed: {}", host, e);
+                        refused = true;
+                        continue;
+                    }
+                };
+                let reply = match twamp::parse(&buf[..n], SystemTime::now()) {
+                    Some(reply) if reply.seq < sent => reply,
+                    _ => continue,
+                };
+                if !seen.insert(reply.seq) {
+                    duplicates += 1;
+                    continue;
+                }
+                let is_reordered = max_seq.is_some_and(|max| reply.seq < max);
+                if is_reordered {
+                    reordered += 1;
+                }
+                max_seq = Some(max_seq.map_or(reply.seq, |max| max.max(reply.seq)));
+                rtt_jitter.update(reply.rtt());
+                forward_jitter.update(reply.forward());
+                backward_jitter.update(reply.backward());
+                rtts.push(reply.rtt() * 1000.0);
+                job.emit(json!({
+                    "summary": false,
+                    "ip": ip,
+                    "seq": reply.seq + 1,
+                    "reflector_seq": reply.reflector_seq,
+                    "duration": reply.rtt() * 1000.0,
+                    "forward": reply.forward() * 1000.0,
+                    "backward": reply.backward() * 1000.0,
+                    "processing": reply.processing() * 1000.0,
+                    "jitter": rtt_jitter.value * 1000.0,
+                    "reordered": is_reordered,
+                }));
+            }
+            _ = time::sleep(timeout.saturating_sub(last_sent.elapsed())), if sent == times => {}
+        }
+    }
+    let received = seen.len() as u32;
+    let avg = if rtts.is_empty() {
+        None
+    } else {
+        Some(rtts.iter().sum::<f64>() / rtts.len() as f64)
+    };
+    let mut result = json!({
+        "summary": true,
+        "ip": ip,
+        "sent": sent,
+        "received": received,
+        "loss": (sent - received) as f64 / sent as f64,
+        "duplicates": duplicates,
+        "reordered": reordered,
+        "min": rtts.iter().copied().reduce(f64::min),
+        "avg": avg,
+        "max": rtts.iter().copied().reduce(f64::max),
+        "jitter": rtt_jitter.value * 1000.0,
+        "forward_jitter": forward_jitter.value * 1000.0,
+        "backward_jitter": backward_jitter.value * 1000.0,
+    });
+    if received == 0 {
+        result["error"] = if refused {
+            json!(SocketIOError::ErrTWAMPFailed)
+        } else {
+            json!(SocketIOError::ErrTWAMPTimeout)
+        };
     }
+    job.emit(result);
+    job.finish();
 }
@@ -0,0 +1,448 @@
+use crate::sockets::{self, Source};
+use crate::tls;
+use bytes::{Buf, Bytes};
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, Response, StatusCode};
+use serde_json::{json, Value};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Redirects followed before giving up, same as the reqwest default.
+pub const MAX_REDIRECTS: usize = 10;
+
+pub struct Options {
+    pub method: Method,
+    pub headers: HeaderMap,
+    pub body: Option<String>,
+    pub follow_redirects: bool,
+    pub timeout: Duration,
+    pub insecure: bool,
+    pub max_body_bytes: usize,
+    pub assertions: Vec<Assertion>,
+    /// Backend address used for the url's host instead of resolving it, like `curl --resolve`.
+    pub resolve: Option<IpAddr>,
+    /// Server name sent in the TLS handshake instead of the url's host.
+    pub sni: Option<String>,
+    /// Protocol to force, negotiated as usual when unset.
+    pub version: Option<Version>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Version {
+    Http1,
+    /// HTTP/2 negotiated with ALPN.
+    Http2,
+    /// HTTP/2 without negotiation, also works for cleartext `http://` urls.
+    Http2PriorKnowledge,
+    Http3,
+}
+
+impl Version {
+    fn parse(data: &Value) -> Option<Option<Version>> {
+        let version = match data["version"].as_str() {
+            None => return Some(None),
+            Some("1.1") => Version::Http1,
+            Some("2") if data["prior_knowledge"].as_bool().unwrap_or(false) => {
+                Version::Http2PriorKnowledge
+            }
+            Some("2") => Version::Http2,
+            Some("3") => Version::Http3,
+            Some(_) => return None,
+        };
+        Some(Some(version))
+    }
+
+    pub fn expected(&self) -> reqwest::Version {
+        match self {
+            Version::Http1 => reqwest::Version::HTTP_11,
+            Version::Http2 | Version::Http2PriorKnowledge => reqwest::Version::HTTP_2,
+            Version::Http3 => reqwest::Version::HTTP_3,
+        }
+    }
+}
+
+impl Options {
+    /// Reads the request options of an http job, `None` if any of them is invalid.
+    pub fn parse(data: &Value) -> Option<Options> {
+        let method = match data["method"].as_str() {
+            Some(method) => Method::from_bytes(method.to_uppercase().as_bytes()).ok()?,
+            None => Method::GET,
+        };
+        let mut headers = HeaderMap::new();
+        if let Some(map) = data["headers"].as_object() {
+            for (name, value) in map {
+                let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+                let value = HeaderValue::from_str(value.as_str()?).ok()?;
+                headers.append(name, value);
+            }
+        }
+        let assertions = match data["assertions"].as_array() {
+            Some(list) => list.iter().map(Assertion::parse).collect::<Option<_>>()?,
+            None => Vec::new(),
+        };
+        let resolve = match data["resolve"].as_str() {
+            Some(ip) => Some(ip.parse().ok()?),
+            None => None,
+        };
+        Some(Options {
+            method,
+            headers,
+            body: data["body"].as_str().map(|body| body.to_string()),
+            follow_redirects: data["follow_redirects"].as_bool().unwrap_or(true),
+            timeout: Duration::from_millis(data["timeout_ms"].as_u64().unwrap_or(10_000)),
+            insecure: data["insecure"].as_bool().unwrap_or(false),
+            max_body_bytes: data["max_body_bytes"].as_u64().unwrap_or(1 << 20) as usize,
+            assertions,
+            resolve,
+            sni: data["sni"].as_str().map(|sni| sni.to_string()),
+            version: Version::parse(data)?,
+        })
+    }
+}
+
+/// The method of the next request when following a redirect, 303 and the legacy
+/// behaviour of 301/302 turn a POST into a GET without body.
+pub fn redirect_method(status: StatusCode, method: &Method) -> Method {
+    match status.as_u16() {
+        303 if method != Method::HEAD => Method::GET,
+        301 | 302 if method == Method::POST => Method::GET,
+        _ => method.clone(),
+    }
+}
+
+/// One request of a redirect chain.
+pub struct Hop<'a> {
+    pub url: &'a Url,
+    pub method: &'a Method,
+    pub body: Option<&'a String>,
+    /// Address the url's host is connected to.
+    pub addr: SocketAddr,
+    pub source: &'a Source,
+    pub sni: Option<&'a str>,
+    pub timeout: Duration,
+}
+
+pub struct Fetched {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    pub truncated: bool,
+    pub version: reqwest::Version,
+    /// QUIC handshake time, only for HTTP/3.
+    pub handshake: Option<Duration>,
+}
+
+impl Fetched {
+    /// Whether the server advertised HTTP/3 in `Alt-Svc`.
+    pub fn alt_svc_h3(&self) -> bool {
+        self.headers
+            .get_all(reqwest::header::ALT_SVC)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| {
+                value
+                    .split(',')
+                    .any(|service| service.trim_start().starts_with("h3"))
+            })
+    }
+}
+
+pub async fn fetch(options: &Options, hop: Hop<'_>) -> Result<Fetched, Error> {
+    if options.version == Some(Version::Http3) {
+        return tokio::time::timeout(hop.timeout, fetch_h3(options, &hop))
+            .await
+            .map_err(|_| "http/3 request timed out")?;
+    }
+    // connect to the sni name so it is sent in the handshake, the Host header keeps the url's host
+    let mut target = hop.url.clone();
+    let mut headers = options.headers.clone();
+    if let Some(sni) = hop.sni {
+        if !headers.contains_key(reqwest::header::HOST) {
+            let host = hop.url.host_str().unwrap_or_default();
+            let authority = match hop.url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            };
+            headers.insert(reqwest::header::HOST, authority.parse()?);
+        }
+        target.set_host(Some(sni))?;
+    }
+    let mut builder = reqwest::Client::builder()
+        .local_address(hop.source.local_ip(hop.addr.ip())?)
+        .resolve(target.host_str().unwrap_or_default(), hop.addr)
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(hop.timeout)
+        .danger_accept_invalid_certs(options.insecure);
+    builder = match options.version {
+        Some(Version::Http1) => builder.http1_only(),
+        Some(Version::Http2PriorKnowledge) => builder.http2_prior_knowledge(),
+        _ => builder,
+    };
+    let mut req = builder
+        .build()?
+        .request(hop.method.clone(), target)
+        .headers(headers);
+    if let Some(body) = hop.body {
+        req = req.body(body.clone());
+    }
+    let mut res = req.send().await?;
+    let (body, truncated) = read_body(&mut res, options.max_body_bytes).await?;
+    Ok(Fetched {
+        status: res.status(),
+        headers: res.headers().clone(),
+        body,
+        truncated,
+        version: res.version(),
+        handshake: None,
+    })
+}
+
+async fn fetch_h3(options: &Options, hop: &Hop<'_>) -> Result<Fetched, Error> {
+    let server_name = hop
+        .sni
+        .or(hop.url.host_str())
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let mut tls = tls::client_config(options.insecure)?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let config = quinn::crypto::rustls::QuicClientConfig::try_from(tls)?;
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        None,
+        sockets::udp_bind(hop.source, hop.addr.ip())?,
+        Arc::new(quinn::TokioRuntime),
+    )?;
+    let start = Instant::now();
+    let conn = endpoint
+        .connect_with(
+            quinn::ClientConfig::new(Arc::new(config)),
+            hop.addr,
+            server_name,
+        )?
+        .await?;
+    let handshake = start.elapsed();
+    let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(conn)).await?;
+    let drive = tokio::spawn(async move { driver.wait_idle().await });
+    let mut req = ::http::Request::builder()
+        .method(hop.method.as_str())
+        .uri(hop.url.as_str());
+    for (name, value) in options.headers.iter() {
+        req = req.header(name.as_str(), value.as_bytes());
+    }
+    let mut stream = send_request.send_request(req.body(())?).await?;
+    if let Some(body) = hop.body {
+        stream.send_data(Bytes::from(body.clone())).await?;
+    }
+    stream.finish().await?;
+    let res = stream.recv_response().await?;
+    let mut body = Vec::new();
+    let mut truncated = false;
+    while let Some(mut chunk) = stream.recv_data().await? {
+        let len = chunk.remaining().min(options.max_body_bytes - body.len());
+        body.extend_from_slice(&chunk.copy_to_bytes(len));
+        if chunk.has_remaining() {
+            truncated = true;
+            break;
+        }
+    }
+    let mut headers = HeaderMap::new();
+    for (name, value) in res.headers() {
+        headers.append(
+            HeaderName::from_bytes(name.as_str().as_bytes())?,
+            HeaderValue::from_bytes(value.as_bytes())?,
+        );
+    }
+    drop(stream);
+    drop(send_request);
+    drive.abort();
+    endpoint.close(0u32.into(), b"");
+    Ok(Fetched {
+        status: StatusCode::from_u16(res.status().as_u16())?,
+        headers,
+        body,
+        truncated,
+        version: reqwest::Version::HTTP_3,
+        handshake: Some(handshake),
+    })
+}
+
+/// Reads the body up to `max` bytes, the flag tells if it was cut.
+pub async fn read_body(res: &mut Response, max: usize) -> reqwest::Result<(Vec<u8>, bool)> {
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if body.len() + chunk.len() > max {
+            body.extend_from_slice(&chunk[..max - body.len()]);
+            return Ok((body, true));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body, false))
+}
+
+pub enum Matcher {
+    Contains(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn parse(spec: &Value) -> Option<Matcher> {
+        if let Some(pattern) = spec["regex"].as_str() {
+            return Regex::new(pattern).ok().map(Matcher::Regex);
+        }
+        spec["value"]
+            .as_str()
+            .map(|value| Matcher::Contains(value.to_string()))
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            Matcher::Contains(value) => text.contains(value.as_str()),
+            Matcher::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+pub enum Check {
+    /// The status is one of the expected codes.
+    Status(Vec<u16>),
+    /// A header value contains a substring or matches a regex.
+    Header { name: HeaderName, matcher: Matcher },
+    /// The body contains a substring or matches a regex.
+    Body(Matcher),
+    /// The value at a JSON path equals the expected value.
+    Json { path: String, value: Value },
+}
+
+pub struct Assertion {
+    spec: Value,
+    check: Check,
+}
+
+impl Assertion {
+    /// Parses `{"type": "status" | "header" | "body" | "json", ...}`.
+    pub fn parse(spec: &Value) -> Option<Assertion> {
+        let check = match spec["type"].as_str()? {
+            "status" => Check::Status(match &spec["value"] {
+                Value::Array(codes) => codes
+                    .iter()
+                    .map(|code| code.as_u64().map(|code| code as u16))
+                    .collect::<Option<_>>()?,
+                code => vec![code.as_u64()? as u16],
+            }),
+            "header" => Check::Header {
+                name: HeaderName::from_bytes(spec["name"].as_str()?.as_bytes()).ok()?,
+                matcher: Matcher::parse(spec)?,
+            },
+            "body" => Check::Body(Matcher::parse(spec)?),
+            "json" => Check::Json {
+                path: spec["path"].as_str()?.to_string(),
+                value: spec.get("value")?.clone(),
+            },
+            _ => return None,
+        };
+        Some(Assertion {
+            spec: spec.clone(),
+            check,
+        })
+    }
+
+    /// Returns the assertion with `passed` and the `actual` value it was checked against.
+    pub fn evaluate(&self, status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Value {
+        let (passed, actual) = match &self.check {
+            Check::Status(codes) => (codes.contains(&status.as_u16()), json!(status.as_u16())),
+            Check::Header { name, matcher } => {
+                let values: Vec<&str> = headers
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .collect();
+                (
+                    values.iter().any(|value| matcher.matches(value)),
+                    json!(values),
+                )
+            }
+            Check::Body(matcher) => (matcher.matches(&String::from_utf8_lossy(body)), Value::Null),
+            Check::Json { path, value } => {
+                let actual = serde_json::from_slice::<Value>(body)
+                    .ok()
+                    .and_then(|doc| json_path(&doc, path).cloned())
+                    .unwrap_or(Value::Null);
+                (actual == *value, actual)
+            }
+        };
+        let mut res = self.spec.clone();
+        res["passed"] = json!(passed);
+        res["actual"] = actual;
+        res
+    }
+}
+
+/// Looks up a dotted path like `$.data.items[0].status`.
+pub fn json_path<'a>(doc: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.trim_start_matches('$').trim_start_matches('.');
+    let mut current = doc;
+    for part in path.split('.').filter(|part| !part.is_empty()) {
+        let (key, indexes) = match part.find('[') {
+            Some(i) => (&part[..i], &part[i..]),
+            None => (part, ""),
+        };
+        if !key.is_empty() {
+            current = current.get(key)?;
+        }
+        for index in indexes.split('[').filter(|index| !index.is_empty()) {
+            let index: usize = index.strip_suffix(']')?.parse().ok()?;
+            current = current.get(index)?;
+        }
+    }
+    Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_path_lookups() {
+        let doc = json!({
+            "status": "ok",
+            "data": {"items": [{"id": 1}, {"id": 2, "tags": ["a", "b"]}]},
+            "matrix": [[1, 2], [3, 4]],
+        });
+        assert_eq!(json_path(&doc, "$.status"), Some(&json!("ok")));
+        assert_eq!(json_path(&doc, "status"), Some(&json!("ok")));
+        assert_eq!(json_path(&doc, "$.data.items[1].id"), Some(&json!(2)));
+        assert_eq!(
+            json_path(&doc, "$.data.items[1].tags[0]"),
+            Some(&json!("a"))
+        );
+        assert_eq!(json_path(&doc, "$.matrix[1][0]"), Some(&json!(3)));
+        assert_eq!(json_path(&doc, "$"), Some(&doc));
+    }
+
+    #[test]
+    fn json_path_misses() {
+        let doc = json!({"data": {"items": [{"id": 1}]}});
+        assert_eq!(json_path(&doc, "$.missing"), None);
+        assert_eq!(json_path(&doc, "$.data.items[3]"), None);
+        assert_eq!(json_path(&doc, "$.data.items[x]"), None);
+        assert_eq!(json_path(&doc, "$.data.items[0"), None);
+        assert_eq!(json_path(&doc, "$.data[0]"), None);
+    }
+
+    #[test]
+    fn json_assertion() {
+        let assertion =
+            Assertion::parse(&json!({"type": "json", "path": "$.a[0]", "value": true})).unwrap();
+        let res = assertion.evaluate(StatusCode::OK, &HeaderMap::new(), br#"{"a": [true]}"#);
+        assert_eq!(res["passed"], json!(true));
+        let res = assertion.evaluate(StatusCode::OK, &HeaderMap::new(), b"not json");
+        assert_eq!(res["passed"], json!(false));
+        assert_eq!(res["actual"], Value::Null);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::errors::SocketIOError;
+use crate::{geoip, metrics, queue, status};
+use serde_json::{json, Value};
+use socketioxide::extract::SocketRef;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::debug;
+
+#[derive(Clone)]
+enum Sink {
+    Socket(SocketRef),
+    Collect(Arc<Mutex<Vec<Value>>>),
+    Tunnel {
+        request: String,
+        tx: UnboundedSender<Value>,
+    },
+}
+
+impl Sink {
+    fn send(&self, kind: &'static str, id: Option<&str>, data: &Value) {
+        let delivered = match self {
+            Sink::Socket(socket) => match socket.emit(kind, data) {
+                Ok(_) => true,
+                Err(e) => {
+                    debug!("emit {} result failed: {}", kind, e);
+                    false
+                }
+            },
+            Sink::Collect(results) => {
+                results.lock().unwrap().push(data.clone());
+                true
+            }
+            Sink::Tunnel { request, tx } => tx
+                .send(json!({
+                    "id": request,
+                    "event": kind,
+                    "data": data,
+                }))
+                .is_ok(),
+        };
+        if let Some(id) = id {
+            queue::enqueue(id, kind, data, delivered);
+        }
+    }
+}
+
+/// A running job, results emitted through it are accounted in the agent status and metrics.
+pub struct Job {
+    kind: &'static str,
+    id: Option<String>,
+    sink: Sink,
+    cancelled: Arc<AtomicBool>,
+    failed: AtomicBool,
+}
+
+impl Job {
+    fn with_sink(kind: &'static str, id: Option<String>, sink: Sink) -> Self {
+        status::job_started(kind);
+        Job {
+            kind,
+            id,
+            sink,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// A job requested by the NodeCook server, results are emitted back on its socket. The
+    /// `cancelled` flag is shared by the jobs of the socket and set when it disconnects.
+    pub fn new(
+        kind: &'static str,
+        socket: SocketRef,
+        cancelled: Arc<AtomicBool>,
+        data: &Value,
+    ) -> Self {
+        let id = data["job_id"].as_str().map(|id| id.to_string());
+        let mut job = Self::with_sink(kind, id, Sink::Socket(socket));
+        job.cancelled = cancelled;
+        job
+    }
+
+    /// A job started by the agent itself, results are collected in the returned list and
+    /// buffered under `id` when one is given.
+    pub fn local(kind: &'static str, id: Option<String>) -> (Self, Arc<Mutex<Vec<Value>>>) {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        (
+            Self::with_sink(kind, id, Sink::Collect(results.clone())),
+            results,
+        )
+    }
+
+    /// A job received over the outbound tunnel, results are sent back tagged with the request id.
+    pub fn tunnel(
+        kind: &'static str,
+        request: String,
+        tx: UnboundedSender<Value>,
+        cancelled: Arc<AtomicBool>,
+        data: &Value,
+    ) -> Self {
+        let id = data["job_id"].as_str().map(|id| id.to_string());
+        let mut job = Self::with_sink(kind, id, Sink::Tunnel { request, tx });
+        job.cancelled = cancelled;
+        job
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// The flag behind `cancelled` for work that can't hold the job, like blocking threads.
+    pub fn cancellation(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    pub fn emit(&self, mut data: Value) {
+        geoip::enrich(self.kind, &mut data);
+        if let Some(error) = data.get("error") {
+            self.failed.store(true, Ordering::Relaxed);
+            status::record_error(self.kind, error);
+        } else if let Some(ms) = data["duration"].as_f64() {
+            metrics::PROBE_DURATION
+                .with_label_values(&[self.kind])
+                .observe(ms / 1000.0);
+        }
+        self.sink.send(self.kind, self.id.as_deref(), &data);
+    }
+
+    /// Reports errors of the job once the job itself is gone.
+    pub fn reporter(&self) -> Reporter {
+        Reporter {
+            kind: self.kind,
+            id: self.id.clone(),
+            sink: self.sink.clone(),
+        }
+    }
+
+    /// Tells the requester that no more results will follow.
+    pub fn finish(&self) {
+        if let Sink::Socket(socket) = &self.sink {
+            socket.disconnect().ok();
+        }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        status::job_finished(self.kind);
+        // a job dropped by a panic of the thread driving it didn't succeed either
+        let success = !self.failed.load(Ordering::Relaxed) && !std::thread::panicking();
+        metrics::JOBS
+            .with_label_values(&[self.kind, metrics::outcome(success)])
+            .inc();
+    }
+}
+
+/// Emits errors for a job that died with the thread driving it, like a job in another network
+/// namespace whose thread panicked.
+pub struct Reporter {
+    kind: &'static str,
+    id: Option<String>,
+    sink: Sink,
+}
+
+impl Reporter {
+    pub fn error(&self, error: SocketIOError) {
+        let data = json!({ "error": error });
+        status::record_error(self.kind, &data["error"]);
+        self.sink.send(self.kind, self.id.as_deref(), &data);
+    }
+}
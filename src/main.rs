@@ -1,15 +1,38 @@
 mod api;
 mod app;
+mod bandwidth;
+mod capability;
 mod cli;
 mod constant;
 mod dns;
 mod errors;
+#[cfg(target_os = "linux")]
+mod errqueue;
+mod geoip;
 mod handlers;
+mod http;
+mod job;
+mod mesh;
+mod metrics;
+mod netns;
+mod ntp;
+mod pmtu;
+mod queue;
+mod schedule;
+mod service;
+mod sockets;
+mod status;
+mod tls;
+mod traceroute;
+mod tunnel;
+mod twamp;
+mod udp;
 mod utils;
 use crate::app::create_app;
 use crate::cli::Cli;
 use crate::utils::add_agent_with_args;
 use clap::Parser;
+use std::time::Duration;
 use tokio::signal;
 use tokio_cron_scheduler::{Job, JobScheduler};
 use tracing::{info, Level};
@@ -26,9 +49,73 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     let collector = fmt().with_max_level(level).finish();
     tracing::subscriber::set_global_default(collector)?;
+    status::init();
+    metrics::init();
     if args.ipv4_only && args.ipv6_only {
         panic!("ipv4_only and ipv6_only can't be true at the same time");
     }
+    sockets::init(args.source_ip, args.interface.clone());
+    capability::self_test().await;
+    geoip::init(args.asn_db.as_deref(), args.geoip_db.as_deref())?;
+    if args.queue_dir.is_some() || args.push_url.is_some() {
+        queue::init(
+            args.queue_dir.clone(),
+            args.queue_max,
+            args.push_url.is_some(),
+        )?;
+    }
+    if let Some(push_url) = args.push_url.clone() {
+        tokio::spawn(queue::push_loop(
+            push_url,
+            api_key.clone(),
+            Duration::from_secs(args.push_interval),
+        ));
+    }
+    if let Some(bandwidth_port) = args.bandwidth_port {
+        tokio::spawn(bandwidth::serve(bandwidth_port, api_key.clone()));
+    }
+    if let Some(twamp_port) = args.twamp_port {
+        tokio::spawn(twamp::serve(twamp_port));
+    }
+    let sched = JobScheduler::new().await?;
+    schedule::add_probes(&sched, &args.probes).await?;
+    if !args.peers.is_empty() || args.peers_url.is_some() {
+        tokio::spawn(mesh::serve_echo(port));
+        mesh::add(
+            &sched,
+            mesh::Config {
+                peers: args.peers.clone(),
+                peers_url: args.peers_url.clone(),
+                probes: args.mesh_probes.clone(),
+                interval: Duration::from_secs(args.mesh_interval),
+                port,
+                api_key: api_key.clone(),
+            },
+        )
+        .await?;
+    }
+    match args.tunnel_url.clone() {
+        Some(tunnel_url) => {
+            tokio::spawn(tunnel::run(
+                tunnel_url,
+                api_key.clone(),
+                args.endpoint.clone(),
+            ));
+        }
+        None => register(&sched, args).await?,
+    }
+    sched.start().await?;
+    let addr = format!(":::{}", port);
+    info!("listening on addr {}", addr);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, create_app(api_key))
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+    Ok(())
+}
+
+/// Registers the agent to the NodeCook servers and keeps the registration alive.
+async fn register(sched: &JobScheduler, args: Cli) -> Result<(), Box<dyn std::error::Error>> {
     let mut v4_ok = false;
     let mut v6_ok = false;
     if !args.ipv4_only {
@@ -40,7 +127,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if !v4_ok && !v6_ok {
         panic!("add ipv4 and ipv6 agent failed, please check your network or try again later");
     }
-    let sched = JobScheduler::new().await?;
     sched
         .add(Job::new_async("*/60 * * * * *", move |_uuid, _l| {
             let args = args.clone();
@@ -54,13 +140,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             })
         })?)
         .await?;
-    sched.start().await?;
-    let addr = format!(":::{}", port);
-    info!("listening on addr {}", addr);
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, create_app(api_key))
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
     Ok(())
 }
 
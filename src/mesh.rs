@@ -0,0 +1,372 @@
+use crate::app::run_job;
+use crate::job::Job;
+use crate::metrics;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio::time;
+use tokio_cron_scheduler::{Job as CronJob, JobScheduler};
+use tracing::{debug, error, info};
+
+/// Prefix of the datagrams answered by the mesh echo responder, anything else is ignored.
+pub const MAGIC: &[u8] = b"nodecook-mesh";
+/// Samples per peer and probe type in every round.
+const SAMPLES: usize = 5;
+
+static PEERS: Mutex<Vec<Peer>> = Mutex::new(Vec::new());
+static MATRIX: LazyLock<Mutex<HashMap<String, HashMap<&'static str, Link>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, PartialEq)]
+pub struct Peer {
+    pub name: String,
+    pub host: String,
+    /// Port the peer agent listens on, over TCP for tcping and UDP for the echo responder.
+    pub port: u16,
+}
+
+impl Peer {
+    /// Parses `[name=]host[:port]`, the name defaults to the host.
+    pub fn parse(spec: &str, default_port: u16) -> Option<Peer> {
+        let (name, addr) = match spec.split_once('=') {
+            Some((name, addr)) => (Some(name.trim()), addr.trim()),
+            None => (None, spec.trim()),
+        };
+        let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        } else {
+            match addr.split_once(':') {
+                // more than one colon is a bare ipv6 address
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                _ => (addr, None),
+            }
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let port = match port {
+            Some(port) => port.parse().ok()?,
+            None => default_port,
+        };
+        Some(Peer {
+            name: name.unwrap_or(host).to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    fn from_json(value: &Value, default_port: u16) -> Option<Peer> {
+        if let Some(spec) = value.as_str() {
+            return Peer::parse(spec, default_port);
+        }
+        let host = value["host"].as_str()?;
+        Some(Peer {
+            name: value["name"].as_str().unwrap_or(host).to_string(),
+            host: host.to_string(),
+            port: match value.get("port") {
+                Some(port) => u16::try_from(port.as_u64()?).ok()?,
+                None => default_port,
+            },
+        })
+    }
+
+    fn request(&self, kind: &str) -> Value {
+        match kind {
+            "tcping" => {
+                let host = if self.host.contains(':') {
+                    format!("[{}]:{}", self.host, self.port)
+                } else {
+                    format!("{}:{}", self.host, self.port)
+                };
+                json!({ "host": host, "single": true, "reverse": false })
+            }
+            "udp" => json!({
+                "host": self.host,
+                "port": self.port,
+                "payload": hex::encode(MAGIC),
+                "encoding": "hex",
+                "is_ipv4": !self.host.contains(':'),
+                "single": true,
+            }),
+            _ => json!({
+                "host": self.host,
+                "is_ipv4": !self.host.contains(':'),
+                "single": true,
+                "reverse": false,
+            }),
+        }
+    }
+}
+
+/// Latency and loss to a peer measured in the last round.
+struct Link {
+    sent: u64,
+    received: u64,
+    /// Milliseconds.
+    last_rtt: Option<f64>,
+    /// Milliseconds.
+    avg_rtt: Option<f64>,
+    updated: u64,
+}
+
+impl Link {
+    fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        (self.sent - self.received) as f64 / self.sent as f64
+    }
+}
+
+pub struct Config {
+    pub peers: Vec<String>,
+    /// Url returning the peers as a json array, fetched before every round.
+    pub peers_url: Option<String>,
+    pub probes: Vec<String>,
+    pub interval: Duration,
+    /// Own port, the default port of the peers.
+    pub port: u16,
+    pub api_key: String,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Answers the udp probes of the other agents of the mesh.
+pub async fn serve_echo(port: u16) {
+    let sock = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+        Ok(sock) => sock,
+        Err(e) => {
+            error!("mesh echo failed to listen on udp port {}: {}", port, e);
+            return;
+        }
+    };
+    let mut buf = [0u8; 2048];
+    loop {
+        let (n, peer) = match sock.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(e) => {
+                debug!("mesh echo receive failed: {}", e);
+                continue;
+            }
+        };
+        if buf[..n].starts_with(MAGIC) {
+            let _ = sock.send_to(&buf[..n], peer).await;
+        }
+    }
+}
+
+async fn fetch_peers(url: &str, api_key: &str, default_port: u16) -> Vec<Peer> {
+    let res = reqwest::Client::new()
+        .get(url)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await;
+    let peers: Value = match res {
+        Ok(res) => res.json().await.unwrap_or(Value::Null),
+        Err(e) => {
+            error!("fetch mesh peers from {} failed: {}", url, e);
+            return Vec::new();
+        }
+    };
+    peers
+        .as_array()
+        .map(|peers| {
+            peers
+                .iter()
+                .filter_map(|peer| Peer::from_json(peer, default_port))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+async fn probe(peer: Peer, kind: &'static str) -> (Peer, &'static str, Link) {
+    let mut rtts = Vec::new();
+    for idx in 0..SAMPLES {
+        if idx > 0 {
+            time::sleep(Duration::from_secs(1)).await;
+        }
+        let (job, results) = Job::local(kind, None);
+        run_job(job, peer.request(kind)).await;
+        let result = results.lock().unwrap().last().cloned();
+        let rtt = result
+            .filter(|result| result.get("error").is_none())
+            .and_then(|result| result["duration"].as_f64());
+        if let Some(rtt) = rtt {
+            rtts.push(rtt);
+        }
+    }
+    let link = Link {
+        sent: SAMPLES as u64,
+        received: rtts.len() as u64,
+        last_rtt: rtts.last().copied(),
+        avg_rtt: if rtts.is_empty() {
+            None
+        } else {
+            Some(rtts.iter().sum::<f64>() / rtts.len() as f64)
+        },
+        updated: unix_now(),
+    };
+    (peer, kind, link)
+}
+
+async fn round(config: &Config, kinds: &[&'static str]) {
+    let mut peers: Vec<Peer> = config
+        .peers
+        .iter()
+        .filter_map(|spec| Peer::parse(spec, config.port))
+        .collect();
+    if let Some(url) = &config.peers_url {
+        for peer in fetch_peers(url, &config.api_key, config.port).await {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+    }
+    debug!("probe {} mesh peers", peers.len());
+    *PEERS.lock().unwrap() = peers.clone();
+    let mut set = JoinSet::new();
+    for peer in peers {
+        for kind in kinds {
+            set.spawn(probe(peer.clone(), *kind));
+        }
+    }
+    while let Some(res) = set.join_next().await {
+        let Ok((peer, kind, link)) = res else {
+            continue;
+        };
+        metrics::observe_mesh(
+            &peer.name,
+            kind,
+            link.avg_rtt.map(|ms| ms / 1000.0),
+            link.loss(),
+        );
+        MATRIX
+            .lock()
+            .unwrap()
+            .entry(peer.name)
+            .or_default()
+            .insert(kind, link);
+    }
+}
+
+/// Schedules probing every peer with every probe type once per interval.
+pub async fn add(sched: &JobScheduler, config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut kinds = Vec::new();
+    for probe in &config.probes {
+        kinds.push(match probe.as_str() {
+            "ping" => "ping",
+            "tcping" => "tcping",
+            "udp" => "udp",
+            other => return Err(format!("unsupported mesh probe type `{}`", other).into()),
+        });
+    }
+    info!(
+        "probe mesh peers with {} every {}s",
+        config.probes.join(","),
+        config.interval.as_secs()
+    );
+    let interval = config.interval;
+    let config = std::sync::Arc::new(config);
+    sched
+        .add(CronJob::new_repeated_async(interval, move |_uuid, _l| {
+            let config = config.clone();
+            let kinds = kinds.clone();
+            Box::pin(async move { round(&config, &kinds).await })
+        })?)
+        .await?;
+    Ok(())
+}
+
+/// The latency and loss matrix from this agent to every peer.
+pub fn report() -> Value {
+    let matrix = MATRIX.lock().unwrap();
+    let peers: Vec<Value> = PEERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|peer| {
+            let mut probes = Map::new();
+            if let Some(links) = matrix.get(&peer.name) {
+                for (kind, link) in links {
+                    probes.insert(
+                        kind.to_string(),
+                        json!({
+                            "sent": link.sent,
+                            "received": link.received,
+                            "loss": link.loss(),
+                            "last_rtt": link.last_rtt,
+                            "avg_rtt": link.avg_rtt,
+                            "updated": link.updated,
+                        }),
+                    );
+                }
+            }
+            json!({
+                "name": peer.name,
+                "host": peer.host,
+                "port": peer.port,
+                "probes": probes,
+            })
+        })
+        .collect();
+    json!({ "peers": peers })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(name: &str, host: &str, port: u16) -> Option<Peer> {
+        Some(Peer {
+            name: name.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    #[test]
+    fn parse_host_and_port() {
+        assert!(Peer::parse("10.0.0.1:9000", 8000) == peer("10.0.0.1", "10.0.0.1", 9000));
+        assert!(Peer::parse("example.com", 8000) == peer("example.com", "example.com", 8000));
+        assert!(Peer::parse(" tokyo = 10.0.0.1 ", 8000) == peer("tokyo", "10.0.0.1", 8000));
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        assert!(
+            Peer::parse("[2001:db8::1]:9000", 8000) == peer("2001:db8::1", "2001:db8::1", 9000)
+        );
+        assert!(Peer::parse("[2001:db8::1]", 8000) == peer("2001:db8::1", "2001:db8::1", 8000));
+        assert!(Peer::parse("2001:db8::1", 8000) == peer("2001:db8::1", "2001:db8::1", 8000));
+        assert!(Peer::parse("fra=[::1]:9000", 8000) == peer("fra", "::1", 9000));
+    }
+
+    #[test]
+    fn parse_rejects() {
+        assert!(Peer::parse("10.0.0.1:http", 8000).is_none());
+        assert!(Peer::parse("10.0.0.1:70000", 8000).is_none());
+        assert!(Peer::parse("[2001:db8::1", 8000).is_none());
+        assert!(Peer::parse("name=", 8000).is_none());
+        assert!(Peer::parse(":9000", 8000).is_none());
+    }
+
+    #[test]
+    fn from_json_port() {
+        let value = json!({ "host": "10.0.0.1", "port": 9000 });
+        assert!(Peer::from_json(&value, 8000) == peer("10.0.0.1", "10.0.0.1", 9000));
+        let value = json!({ "host": "10.0.0.1", "port": 70000 });
+        assert!(Peer::from_json(&value, 8000).is_none());
+        assert!(Peer::from_json(&json!("b=10.0.0.2"), 8000) == peer("b", "10.0.0.2", 8000));
+    }
+}
@@ -0,0 +1,224 @@
+use prometheus::core::Collector;
+use prometheus::{
+    exponential_buckets, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, TextEncoder,
+};
+use std::sync::LazyLock;
+
+fn register<T: Collector + Clone + 'static>(collector: T) -> T {
+    prometheus::register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+pub static JOBS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("nodecook_agent_jobs_total", "Jobs run by type and outcome"),
+            &["type", "outcome"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static PROBE_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "nodecook_agent_probe_duration_seconds",
+                "Duration of single probes by job type",
+            )
+            .buckets(exponential_buckets(0.001, 2.0, 15).unwrap()),
+            &["type"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static SOCKET_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "nodecook_agent_socket_connections",
+            "Active socket.io connections",
+        )
+        .unwrap(),
+    )
+});
+
+pub static AUTH_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "nodecook_agent_auth_failures_total",
+            "Requests rejected because of a wrong or missing api key",
+        )
+        .unwrap(),
+    )
+});
+
+pub static DNS_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "nodecook_agent_dns_failures_total",
+                "Failed dns lookups by nameserver",
+            ),
+            &["nameserver"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static REGISTRATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "nodecook_agent_registrations_total",
+                "Registrations to the NodeCook server by ip type and outcome",
+            ),
+            &["ip_type", "outcome"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static PROBE_SUCCESS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "nodecook_agent_probe_success",
+                "Whether the last run of a scheduled probe succeeded",
+            ),
+            &["type", "target"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static PROBE_LAST_DURATION: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new(
+                "nodecook_agent_probe_last_duration_seconds",
+                "Duration of the last run of a scheduled probe",
+            ),
+            &["type", "target"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static PROBE_HTTP_STATUS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "nodecook_agent_probe_http_status_code",
+                "Response status code of the last run of a scheduled http probe",
+            ),
+            &["type", "target"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static SCHEDULED_PROBE_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "nodecook_agent_scheduled_probe_duration_seconds",
+                "Duration of scheduled probes",
+            )
+            .buckets(exponential_buckets(0.001, 2.0, 15).unwrap()),
+            &["type", "target"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static MESH_RTT: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new(
+                "nodecook_agent_mesh_rtt_seconds",
+                "Average round trip time to a mesh peer in the last round",
+            ),
+            &["peer", "type"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static MESH_LOSS: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new(
+                "nodecook_agent_mesh_loss_ratio",
+                "Ratio of lost probes to a mesh peer in the last round",
+            ),
+            &["peer", "type"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Registers every metric so they are exported before their first update.
+pub fn init() {
+    LazyLock::force(&JOBS);
+    LazyLock::force(&PROBE_DURATION);
+    LazyLock::force(&SOCKET_CONNECTIONS);
+    LazyLock::force(&AUTH_FAILURES);
+    LazyLock::force(&DNS_FAILURES);
+    LazyLock::force(&REGISTRATIONS);
+    LazyLock::force(&PROBE_SUCCESS);
+    LazyLock::force(&PROBE_LAST_DURATION);
+    LazyLock::force(&PROBE_HTTP_STATUS);
+    LazyLock::force(&SCHEDULED_PROBE_DURATION);
+    LazyLock::force(&MESH_RTT);
+    LazyLock::force(&MESH_LOSS);
+}
+
+pub fn outcome(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+pub fn observe_scheduled_probe(
+    kind: &str,
+    target: &str,
+    success: bool,
+    duration: Option<f64>,
+    status: Option<u64>,
+) {
+    let labels = [kind, target];
+    PROBE_SUCCESS.with_label_values(&labels).set(success as i64);
+    if let Some(duration) = duration {
+        PROBE_LAST_DURATION.with_label_values(&labels).set(duration);
+        SCHEDULED_PROBE_DURATION
+            .with_label_values(&labels)
+            .observe(duration);
+    }
+    if let Some(status) = status {
+        PROBE_HTTP_STATUS
+            .with_label_values(&labels)
+            .set(status as i64);
+    }
+}
+
+pub fn observe_mesh(peer: &str, kind: &str, rtt: Option<f64>, loss: f64) {
+    let labels = [peer, kind];
+    MESH_LOSS.with_label_values(&labels).set(loss);
+    match rtt {
+        Some(rtt) => MESH_RTT.with_label_values(&labels).set(rtt),
+        // an unreachable peer has no round trip time, drop the stale one
+        None => {
+            let _ = MESH_RTT.remove_label_values(&labels);
+        }
+    }
+}
+
+pub fn render() -> String {
+    TextEncoder::new()
+        .encode_to_string(&prometheus::gather())
+        .unwrap_or_default()
+}
@@ -0,0 +1,74 @@
+use std::io;
+use std::path::PathBuf;
+
+/// Where `ip netns add` mounts the named namespaces.
+const NETNS_DIR: &str = "/run/netns";
+
+/// The namespace file of the namespace named `netns` like `ip netns exec` looks it up, `None`
+/// for anything but a bare name or its path in `/run/netns` so requests can't open namespaces
+/// of other processes.
+pub fn path(netns: &str) -> Option<PathBuf> {
+    let netns = netns
+        .strip_prefix(NETNS_DIR)
+        .and_then(|name| name.strip_prefix('/'))
+        .unwrap_or(netns);
+    if netns.is_empty() || netns == "." || netns == ".." || netns.contains('/') {
+        return None;
+    }
+    Some(PathBuf::from(NETNS_DIR).join(netns))
+}
+
+/// Moves the calling thread into the network namespace at `path`, sockets it opens from then
+/// on and threads it spawns live there. Needs `CAP_SYS_ADMIN`.
+#[cfg(target_os = "linux")]
+pub fn enter(path: &std::path::Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let file = std::fs::File::open(path)?;
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enter(_path: &std::path::Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "network namespaces are only supported on linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_namespaces() {
+        assert_eq!(path("blue"), Some(PathBuf::from("/run/netns/blue")));
+        assert_eq!(path("vrf-a.1"), Some(PathBuf::from("/run/netns/vrf-a.1")));
+        assert_eq!(
+            path("/run/netns/blue"),
+            Some(PathBuf::from("/run/netns/blue"))
+        );
+    }
+
+    #[test]
+    fn rejects_paths() {
+        for netns in [
+            "",
+            ".",
+            "..",
+            "/proc/1/ns/net",
+            "../../proc/1/ns/net",
+            "blue/",
+            "/run/netns",
+            "/run/netns/",
+            "/run/netns/..",
+            "/run/netns/a/b",
+            "/run/netnsblue",
+            "/run/netns/../../proc/1/ns/net",
+        ] {
+            assert_eq!(path(netns), None, "{}", netns);
+        }
+    }
+}
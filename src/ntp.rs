@@ -0,0 +1,193 @@
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds between the NTP epoch (1900) and the unix epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+pub const PORT: u16 = 123;
+
+pub struct Response {
+    pub leap: u8,
+    pub version: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    /// Seconds.
+    pub root_delay: f64,
+    /// Seconds.
+    pub root_dispersion: f64,
+    pub reference_id: String,
+    /// Unix timestamp in seconds.
+    pub reference_time: f64,
+    /// Round-trip delay in seconds, excluding the processing time of the server.
+    pub delay: f64,
+    /// Offset of the server clock to the local clock in seconds.
+    pub offset: f64,
+}
+
+pub fn unix_seconds(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+pub fn to_timestamp(t: SystemTime) -> u64 {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = (d.as_secs() + NTP_UNIX_OFFSET) & 0xffff_ffff;
+    let frac = ((d.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
+
+pub fn from_timestamp(ts: u64) -> f64 {
+    if ts == 0 {
+        return 0.0;
+    }
+    let mut secs = ts >> 32;
+    // RFC 4330: timestamps with the most significant bit unset are in era 1 (after 2036)
+    if secs & 0x8000_0000 == 0 {
+        secs += 1 << 32;
+    }
+    secs as f64 - NTP_UNIX_OFFSET as f64 + (ts & 0xffff_ffff) as f64 / 4_294_967_296.0
+}
+
+fn short(buf: &[u8]) -> f64 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64 / 65536.0
+}
+
+fn timestamp(buf: &[u8]) -> u64 {
+    u64::from_be_bytes(buf[..8].try_into().unwrap())
+}
+
+/// Builds an SNTP v4 client request, the transmit timestamp is echoed back as origin timestamp.
+pub fn request(now: SystemTime) -> [u8; 48] {
+    let mut packet = [0u8; 48];
+    // LI 0, version 4, mode 3 (client)
+    packet[0] = 0x23;
+    packet[40..48].copy_from_slice(&to_timestamp(now).to_be_bytes());
+    packet
+}
+
+/// Parses a server response, `None` if it is malformed or doesn't answer `request`.
+pub fn parse(
+    request: &[u8; 48],
+    response: &[u8],
+    sent: SystemTime,
+    received: SystemTime,
+) -> Option<Response> {
+    if response.len() < 48 || response[24..32] != request[40..48] {
+        return None;
+    }
+    let mode = response[0] & 0x07;
+    if mode != 4 && mode != 5 {
+        return None;
+    }
+    let stratum = response[1];
+    let reference_id = if stratum <= 1 {
+        // kiss code for stratum 0, reference clock source for stratum 1
+        String::from_utf8_lossy(&response[12..16])
+            .trim_end_matches('\0')
+            .to_string()
+    } else {
+        Ipv4Addr::new(response[12], response[13], response[14], response[15]).to_string()
+    };
+    let t1 = unix_seconds(sent);
+    let t2 = from_timestamp(timestamp(&response[32..40]));
+    let t3 = from_timestamp(timestamp(&response[40..48]));
+    let t4 = unix_seconds(received);
+    Some(Response {
+        leap: response[0] >> 6,
+        version: (response[0] >> 3) & 0x07,
+        stratum,
+        poll: response[2] as i8,
+        precision: response[3] as i8,
+        root_delay: short(&response[4..8]),
+        root_dispersion: short(&response[8..12]),
+        reference_id,
+        reference_time: from_timestamp(timestamp(&response[16..24])),
+        delay: (t4 - t1) - (t3 - t2),
+        offset: ((t2 - t1) + (t3 - t4)) / 2.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: f64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs_f64(secs)
+    }
+
+    fn response(request: &[u8; 48], stratum: u8, t2: SystemTime, t3: SystemTime) -> [u8; 48] {
+        let mut packet = [0u8; 48];
+        // LI 0, version 4, mode 4 (server)
+        packet[0] = 0x24;
+        packet[1] = stratum;
+        packet[2] = 6;
+        packet[3] = 0xe9;
+        packet[4..8].copy_from_slice(&0x0000_8000u32.to_be_bytes());
+        packet[12..16].copy_from_slice(&[192, 0, 2, 1]);
+        packet[24..32].copy_from_slice(&request[40..48]);
+        packet[32..40].copy_from_slice(&to_timestamp(t2).to_be_bytes());
+        packet[40..48].copy_from_slice(&to_timestamp(t3).to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        let t = at(1_700_000_000.25);
+        assert!((from_timestamp(to_timestamp(t)) - 1_700_000_000.25).abs() < 1e-6);
+        assert_eq!(from_timestamp(0), 0.0);
+    }
+
+    #[test]
+    fn timestamps_after_2036_are_in_era_1() {
+        // 2040-01-01, the NTP seconds wrapped in 2036
+        let t = at(2_208_988_800.0);
+        assert!(to_timestamp(t) >> 63 == 0);
+        assert!((from_timestamp(to_timestamp(t)) - 2_208_988_800.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parse_delay_and_offset() {
+        let sent = at(1_700_000_000.0);
+        let request = request(sent);
+        assert_eq!(request[0], 0x23);
+        // the server clock is 0.5s ahead, 10ms each way and 5ms to answer
+        let packet = response(&request, 2, at(1_700_000_000.51), at(1_700_000_000.515));
+        let res = parse(&request, &packet, sent, at(1_700_000_000.025)).unwrap();
+        assert_eq!(res.version, 4);
+        assert_eq!(res.stratum, 2);
+        assert_eq!(res.poll, 6);
+        assert_eq!(res.precision, -23);
+        assert_eq!(res.root_delay, 0.5);
+        assert_eq!(res.reference_id, "192.0.2.1");
+        assert!((res.delay - 0.02).abs() < 1e-6);
+        assert!((res.offset - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parse_kiss_code() {
+        let sent = at(1_700_000_000.0);
+        let request = request(sent);
+        let mut packet = response(&request, 0, sent, sent);
+        packet[12..16].copy_from_slice(b"RATE");
+        let res = parse(&request, &packet, sent, sent).unwrap();
+        assert_eq!(res.stratum, 0);
+        assert_eq!(res.reference_id, "RATE");
+    }
+
+    #[test]
+    fn parse_rejects_other_answers() {
+        let sent = at(1_700_000_000.0);
+        let request = request(sent);
+        let packet = response(&request, 2, sent, sent);
+        assert!(parse(&request, &packet[..47], sent, sent).is_none());
+        let mut other = packet;
+        other[31] ^= 1;
+        assert!(parse(&request, &other, sent, sent).is_none());
+        let mut client = packet;
+        client[0] = 0x23;
+        assert!(parse(&request, &client, sent, sent).is_none());
+    }
+}
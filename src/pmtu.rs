@@ -0,0 +1,275 @@
+use crate::sockets::Source;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Traceroute's first port, usually closed so the target answers with "port unreachable".
+pub const PORT: u16 = 33434;
+
+#[derive(Clone, Copy)]
+pub enum Mode {
+    /// Probes of increasing size by `step` bytes until one doesn't get through.
+    Linear(u32),
+    Binary,
+}
+
+pub enum Outcome {
+    /// The probe reached the target.
+    Reached,
+    /// A hop or the local interface reported a smaller MTU.
+    TooBig { mtu: u32, hop: Option<IpAddr> },
+    /// The probe was rejected on the way for another reason.
+    Unreachable { hop: Option<IpAddr> },
+    /// No answer, probes silently dropped are the sign of a MTU blackhole.
+    Lost,
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Reached => "reached",
+            Outcome::TooBig { .. } => "too_big",
+            Outcome::Unreachable { .. } => "unreachable",
+            Outcome::Lost => "lost",
+        }
+    }
+}
+
+pub struct Report {
+    pub mtu: u32,
+    /// MTU of the local route to the target, the upper bound of the search.
+    pub local_mtu: u32,
+    /// The hop that sent "fragmentation needed" or "packet too big", if any.
+    pub hop: Option<IpAddr>,
+    pub probes: Vec<(u32, Outcome)>,
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{Mode, Outcome, Report};
+    use crate::errqueue::{self, Readiness};
+    use crate::sockets::{self, Source};
+    use std::io;
+    use std::net::{IpAddr, SocketAddr, UdpSocket};
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::time::Duration;
+
+    const RETRIES: u32 = 3;
+
+    struct Prober {
+        sock: UdpSocket,
+        is_ipv4: bool,
+        timeout: Duration,
+        buf: Vec<u8>,
+    }
+
+    impl Prober {
+        fn fd(&self) -> RawFd {
+            self.sock.as_raw_fd()
+        }
+
+        fn header(&self) -> u32 {
+            // ip + udp headers
+            if self.is_ipv4 {
+                28
+            } else {
+                48
+            }
+        }
+
+        fn route_mtu(&self) -> io::Result<u32> {
+            let mtu = if self.is_ipv4 {
+                errqueue::getsockopt(self.fd(), libc::IPPROTO_IP, libc::IP_MTU)?
+            } else {
+                errqueue::getsockopt(self.fd(), libc::IPPROTO_IPV6, libc::IPV6_MTU)?
+            };
+            Ok(mtu as u32)
+        }
+
+        fn probe_once(&mut self, size: u32) -> io::Result<Outcome> {
+            let payload = vec![0u8; size.saturating_sub(self.header()) as usize];
+            if let Err(e) = self.sock.send(&payload) {
+                if e.raw_os_error() == Some(libc::EMSGSIZE) {
+                    return Ok(Outcome::TooBig {
+                        mtu: self.route_mtu()?,
+                        hop: None,
+                    });
+                }
+                return Err(e);
+            }
+            loop {
+                match errqueue::wait(self.fd(), self.timeout)? {
+                    Readiness::Timeout => return Ok(Outcome::Lost),
+                    Readiness::Data => {
+                        self.sock.recv(&mut self.buf)?;
+                        return Ok(Outcome::Reached);
+                    }
+                    Readiness::Error => {
+                        let Some(e) = errqueue::recv(self.fd(), &mut self.buf)? else {
+                            continue;
+                        };
+                        if e.is_port_unreachable() {
+                            return Ok(Outcome::Reached);
+                        }
+                        if e.is_too_big() {
+                            return Ok(Outcome::TooBig {
+                                mtu: e.info,
+                                hop: e.offender,
+                            });
+                        }
+                        return Ok(Outcome::Unreachable { hop: e.offender });
+                    }
+                }
+            }
+        }
+
+        fn probe(&mut self, size: u32, probes: &mut Vec<(u32, Outcome)>) -> io::Result<bool> {
+            let mut outcome = Outcome::Lost;
+            for _ in 0..RETRIES {
+                outcome = self.probe_once(size)?;
+                if !matches!(outcome, Outcome::Lost) {
+                    break;
+                }
+            }
+            let reached = matches!(outcome, Outcome::Reached);
+            probes.push((size, outcome));
+            Ok(reached)
+        }
+    }
+
+    /// The largest known MTU below `size` reported by the last failed probe.
+    fn hint(probes: &[(u32, Outcome)], size: u32, ok: u32) -> Option<(u32, Option<IpAddr>)> {
+        match probes.last() {
+            Some((_, Outcome::TooBig { mtu, hop })) if *mtu > ok && *mtu < size => {
+                Some((*mtu, *hop))
+            }
+            _ => None,
+        }
+    }
+
+    fn hop(probes: &[(u32, Outcome)]) -> Option<IpAddr> {
+        probes.iter().rev().find_map(|(_, outcome)| match outcome {
+            Outcome::TooBig { hop, .. } => *hop,
+            _ => None,
+        })
+    }
+
+    pub fn discover(
+        target: SocketAddr,
+        source: &Source,
+        mode: Mode,
+        timeout: Duration,
+    ) -> io::Result<Report> {
+        let is_ipv4 = target.is_ipv4();
+        let sock = sockets::udp_bind(source, target.ip())?;
+        sock.set_nonblocking(false)?;
+        sock.connect(target)?;
+        let fd = sock.as_raw_fd();
+        // set DF and ignore the cached path MTU so bigger probes still go out
+        if is_ipv4 {
+            errqueue::setsockopt(
+                fd,
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_PROBE,
+            )?;
+        } else {
+            errqueue::setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_PROBE,
+            )?;
+        }
+        errqueue::enable(fd, is_ipv4)?;
+        let mut prober = Prober {
+            sock,
+            is_ipv4,
+            timeout,
+            buf: vec![0u8; 65535],
+        };
+        let local_mtu = prober.route_mtu()?;
+        let mut probes = Vec::new();
+        // the minimum MTU every link has to support
+        let min = if is_ipv4 { 576 } else { 1280 };
+        if !prober.probe(min, &mut probes)? {
+            return Err(io::Error::other(
+                "target doesn't answer minimum sized probes",
+            ));
+        }
+        let mut ok = min;
+        match mode {
+            Mode::Linear(step) => {
+                let mut size = ok;
+                while size < local_mtu {
+                    size = (size + step.max(1)).min(local_mtu);
+                    if prober.probe(size, &mut probes)? {
+                        ok = size;
+                        continue;
+                    }
+                    if let Some((mtu, _)) = hint(&probes, size, ok) {
+                        if prober.probe(mtu, &mut probes)? {
+                            ok = mtu;
+                        }
+                    }
+                    break;
+                }
+            }
+            Mode::Binary => {
+                let mut bad = local_mtu + 1;
+                if local_mtu > ok && prober.probe(local_mtu, &mut probes)? {
+                    ok = local_mtu;
+                } else {
+                    // a local MTU at or below the minimum leaves nothing to search
+                    bad = local_mtu.max(ok + 1);
+                }
+                while bad - ok > 1 {
+                    let mid = (ok + bad) / 2;
+                    if prober.probe(mid, &mut probes)? {
+                        ok = mid;
+                        continue;
+                    }
+                    bad = mid;
+                    if let Some((mtu, _)) = hint(&probes, mid, ok) {
+                        if prober.probe(mtu, &mut probes)? {
+                            ok = mtu;
+                        } else {
+                            bad = mtu;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Report {
+            mtu: ok,
+            local_mtu,
+            hop: hop(&probes),
+            probes,
+        })
+    }
+}
+
+/// Finds the path MTU with DF-flagged UDP probes, errors on the way are read from the socket
+/// error queue so no raw socket is needed.
+#[cfg(target_os = "linux")]
+pub fn discover(
+    target: SocketAddr,
+    source: &Source,
+    mode: Mode,
+    timeout: Duration,
+) -> io::Result<Report> {
+    linux::discover(target, source, mode, timeout)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn discover(
+    _target: SocketAddr,
+    _source: &Source,
+    _mode: Mode,
+    _timeout: Duration,
+) -> io::Result<Report> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "path MTU discovery is only supported on linux",
+    ))
+}
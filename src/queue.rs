@@ -0,0 +1,547 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use tracing::{debug, error, info};
+
+const PUSH_RETRIES: u32 = 3;
+
+static QUEUE: OnceLock<Queue> = OnceLock::new();
+
+enum Storage {
+    /// One `<sha256 of job_id>.jsonl` file per job, renamed to `.sending` while it is delivered.
+    Disk(PathBuf),
+    Memory(HashMap<String, Vec<Value>>),
+}
+
+/// Buffers results keyed by job id until they are pushed to the collector or fetched by the server.
+struct Queue {
+    storage: Mutex<Storage>,
+    /// Results buffered at most, the oldest are dropped beyond it.
+    max: usize,
+    /// Results buffered now, counted under the storage lock.
+    len: Mutex<usize>,
+    push: bool,
+}
+
+pub struct Batch {
+    pub job_id: String,
+    pub results: Vec<Value>,
+    file: Option<PathBuf>,
+}
+
+/// Job ids are hashed into file names so any id is safe to use and no two ids share a file.
+fn file_name(job_id: &str) -> String {
+    hex::encode(Sha256::digest(job_id.as_bytes()))
+}
+
+fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+/// Where a batch file goes while it is delivered, never over an undelivered one of the same job.
+fn sending_path(path: &Path) -> PathBuf {
+    let millis = unix_millis();
+    let mut sending = path.with_extension(format!("{}.sending", millis));
+    let mut n = 1;
+    while sending.exists() {
+        sending = path.with_extension(format!("{}-{}.sending", millis, n));
+        n += 1;
+    }
+    sending
+}
+
+fn count_lines(path: &Path) -> usize {
+    fs::read_to_string(path)
+        .map(|content| content.lines().count())
+        .unwrap_or_default()
+}
+
+/// Reads a batch file, files without any result are moved aside as `.corrupt` so they are
+/// not retried forever.
+fn read_batch(path: PathBuf) -> Option<Batch> {
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            error!("read queued results {} failed: {}", path.display(), e);
+            return None;
+        }
+    };
+    let results: Vec<Value> = content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let job_id = results
+        .first()
+        .and_then(|result| result["job_id"].as_str())
+        .map(|job_id| job_id.to_string());
+    let Some(job_id) = job_id else {
+        let corrupt = path.with_extension("corrupt");
+        error!(
+            "queued results {} can't be parsed, moved to {}",
+            path.display(),
+            corrupt.display()
+        );
+        if let Err(e) = fs::rename(&path, &corrupt) {
+            error!("move aside {} failed: {}", path.display(), e);
+        }
+        return None;
+    };
+    Some(Batch {
+        job_id,
+        results,
+        file: Some(path),
+    })
+}
+
+impl Queue {
+    fn append(&self, record: Value) {
+        let job_id = record["job_id"].as_str().unwrap_or_default().to_string();
+        let mut storage = self.storage.lock().unwrap();
+        let mut len = self.len.lock().unwrap();
+        while *len >= self.max {
+            match drop_oldest(&mut storage) {
+                Some(dropped) => *len = len.saturating_sub(dropped),
+                None => break,
+            }
+        }
+        *len += 1;
+        match &mut *storage {
+            Storage::Disk(dir) => {
+                let path = dir.join(format!("{}.jsonl", file_name(&job_id)));
+                let res = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .and_then(|mut file| writeln!(file, "{}", record));
+                if let Err(e) = res {
+                    error!("queue result to {} failed: {}", path.display(), e);
+                }
+            }
+            Storage::Memory(jobs) => jobs.entry(job_id).or_default().push(record),
+        }
+    }
+
+    fn take(&self, job_id: Option<&str>) -> Vec<Batch> {
+        let mut storage = self.storage.lock().unwrap();
+        match &mut *storage {
+            Storage::Disk(dir) => {
+                let entries = match fs::read_dir(&*dir) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        error!("read queue dir {} failed: {}", dir.display(), e);
+                        return Vec::new();
+                    }
+                };
+                let prefix = job_id.map(file_name);
+                let mut files = Vec::new();
+                // listed up front so the files renamed below don't show up again
+                let paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+                for path in paths {
+                    let stem = path
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .unwrap_or_default();
+                    if let Some(prefix) = &prefix {
+                        if stem.split('.').next() != Some(prefix.as_str()) {
+                            continue;
+                        }
+                    }
+                    match path.extension().and_then(|e| e.to_str()) {
+                        Some("jsonl") => {
+                            let sending = sending_path(&path);
+                            if fs::rename(&path, &sending).is_ok() {
+                                files.push(sending);
+                            }
+                        }
+                        Some("sending") => files.push(path),
+                        _ => {}
+                    }
+                }
+                let mut batches = Vec::new();
+                let mut corrupt = 0;
+                for path in files {
+                    match read_batch(path.clone()) {
+                        Some(batch) => batches.push(batch),
+                        None if !path.exists() => {
+                            corrupt += count_lines(&path.with_extension("corrupt"));
+                        }
+                        None => {}
+                    }
+                }
+                let mut len = self.len.lock().unwrap();
+                *len = len.saturating_sub(corrupt);
+                batches
+            }
+            Storage::Memory(jobs) => {
+                let ids: Vec<String> = match job_id {
+                    Some(job_id) => vec![job_id.to_string()],
+                    None => jobs.keys().cloned().collect(),
+                };
+                ids.into_iter()
+                    .filter_map(|job_id| {
+                        let results = jobs.remove(&job_id)?;
+                        Some(Batch {
+                            job_id,
+                            results,
+                            file: None,
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn ack(&self, batch: Batch) {
+        if let Some(file) = &batch.file {
+            match fs::remove_file(file) {
+                Ok(_) => {}
+                // dropped while it was delivered, it is out of the count already
+                Err(e) if e.kind() == ErrorKind::NotFound => return,
+                Err(e) => {
+                    error!("remove delivered results {} failed: {}", file.display(), e);
+                    return;
+                }
+            }
+        }
+        let mut len = self.len.lock().unwrap();
+        *len = len.saturating_sub(batch.results.len());
+    }
+
+    /// Keeps an undelivered batch for the next round, files stay as `.sending` on disk.
+    fn nack(&self, batch: Batch) {
+        if batch.file.is_some() {
+            return;
+        }
+        let mut storage = self.storage.lock().unwrap();
+        if let Storage::Memory(jobs) = &mut *storage {
+            let results = jobs.entry(batch.job_id).or_default();
+            let newer = std::mem::replace(results, batch.results);
+            results.extend(newer);
+        }
+    }
+}
+
+/// Batch files in `dir`, both waiting and being delivered.
+fn queued_files(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("read queue dir {} failed: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("jsonl") | Some("sending")
+            )
+        })
+        .collect()
+}
+
+/// Drops the oldest buffered results to make room, returns how many went or `None` when
+/// nothing is left to drop. On disk the oldest file goes as a whole.
+fn drop_oldest(storage: &mut Storage) -> Option<usize> {
+    match storage {
+        Storage::Disk(dir) => {
+            let oldest = queued_files(dir).into_iter().min_by_key(|path| {
+                fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .unwrap_or(UNIX_EPOCH)
+            })?;
+            let dropped = count_lines(&oldest);
+            fs::remove_file(&oldest).ok()?;
+            error!(
+                "queue is full, dropped {} results in {}",
+                dropped,
+                oldest.display()
+            );
+            Some(dropped)
+        }
+        Storage::Memory(jobs) => {
+            let job_id = jobs
+                .iter()
+                .min_by_key(|(_, results)| results.first().and_then(|r| r["time"].as_u64()))
+                .map(|(job_id, _)| job_id.clone())?;
+            let results = jobs.get_mut(&job_id)?;
+            if !results.is_empty() {
+                results.remove(0);
+            }
+            if results.is_empty() {
+                jobs.remove(&job_id);
+            }
+            error!("queue is full, dropped the oldest result of job {}", job_id);
+            Some(1)
+        }
+    }
+}
+
+/// Enables buffering, results are kept on disk when `dir` is set and in memory otherwise. At
+/// most `max` results are kept, the oldest are dropped to make room for new ones.
+pub fn init(dir: Option<PathBuf>, max: usize, push: bool) -> std::io::Result<()> {
+    let (storage, len) = match dir {
+        Some(dir) => {
+            fs::create_dir_all(&dir)?;
+            let len = queued_files(&dir)
+                .iter()
+                .map(|path| count_lines(path))
+                .sum();
+            (Storage::Disk(dir), len)
+        }
+        None => (Storage::Memory(HashMap::new()), 0),
+    };
+    QUEUE.get_or_init(|| Queue {
+        storage: Mutex::new(storage),
+        max: max.max(1),
+        len: Mutex::new(len),
+        push,
+    });
+    Ok(())
+}
+
+/// Buffers a result when it is pushed to the collector or could not be delivered live.
+pub fn enqueue(job_id: &str, kind: &str, result: &Value, delivered: bool) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    if delivered && !queue.push {
+        return;
+    }
+    queue.append(json!({
+        "job_id": job_id,
+        "type": kind,
+        "time": unix_millis(),
+        "result": result,
+    }));
+}
+
+/// Drains the buffered results of a job.
+pub fn take(job_id: &str) -> Vec<Value> {
+    let Some(queue) = QUEUE.get() else {
+        return Vec::new();
+    };
+    let mut results = Vec::new();
+    for batch in queue.take(Some(job_id)) {
+        results.extend(batch.results.iter().cloned());
+        queue.ack(batch);
+    }
+    results
+}
+
+async fn push_batch(client: &reqwest::Client, url: &str, api_key: &str, batch: &Batch) -> bool {
+    let mut delay = Duration::from_secs(1);
+    for attempt in 1..=PUSH_RETRIES {
+        let res = client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .json(&json!({
+                "job_id": batch.job_id,
+                "results": batch.results,
+            }))
+            .send()
+            .await;
+        match res {
+            Ok(res) if res.status().is_success() => return true,
+            Ok(res) => debug!(
+                "push results of job {} failed (attempt {}): {}",
+                batch.job_id,
+                attempt,
+                res.status()
+            ),
+            Err(e) => debug!(
+                "push results of job {} failed (attempt {}): {}",
+                batch.job_id, attempt, e
+            ),
+        }
+        if attempt < PUSH_RETRIES {
+            time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    false
+}
+
+/// Periodically posts the buffered results to the collector in batches per job.
+pub async fn push_loop(url: String, api_key: String, every: Duration) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    info!("push results to {} every {}s", url, every.as_secs());
+    let client = reqwest::Client::new();
+    let mut interval = time::interval(every);
+    loop {
+        interval.tick().await;
+        for batch in queue.take(None) {
+            if push_batch(&client, &url, &api_key, &batch).await {
+                debug!(
+                    "pushed {} results of job {}",
+                    batch.results.len(),
+                    batch.job_id
+                );
+                queue.ack(batch);
+            } else {
+                error!("push results of job {} failed, will retry", batch.job_id);
+                queue.nack(batch);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nca-queue-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn queue(storage: Storage, max: usize) -> Queue {
+        Queue {
+            storage: Mutex::new(storage),
+            max,
+            len: Mutex::new(0),
+            push: true,
+        }
+    }
+
+    fn record(job_id: &str, time: u64) -> Value {
+        json!({ "job_id": job_id, "type": "ping", "time": time, "result": {} })
+    }
+
+    fn times(batch: &Batch) -> Vec<u64> {
+        batch
+            .results
+            .iter()
+            .filter_map(|r| r["time"].as_u64())
+            .collect()
+    }
+
+    #[test]
+    fn file_names_are_distinct() {
+        assert_ne!(file_name("a/b"), file_name("a_b"));
+        assert_eq!(file_name("a/b"), file_name("a/b"));
+        assert!(file_name("../x").chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn disk_append_take_ack() {
+        let dir = temp_dir("ack");
+        let queue = queue(Storage::Disk(dir.clone()), 100);
+        queue.append(record("a/b", 1));
+        queue.append(record("a_b", 2));
+        queue.append(record("a/b", 3));
+        assert_eq!(*queue.len.lock().unwrap(), 3);
+
+        let batches = queue.take(Some("a/b"));
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].job_id, "a/b");
+        assert_eq!(times(&batches[0]), vec![1, 3]);
+        for batch in batches {
+            queue.ack(batch);
+        }
+        assert_eq!(*queue.len.lock().unwrap(), 1);
+        assert!(queue.take(Some("a/b")).is_empty());
+
+        let batches = queue.take(None);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].job_id, "a_b");
+        queue.ack(batches.into_iter().next().unwrap());
+        assert_eq!(*queue.len.lock().unwrap(), 0);
+        assert!(queued_files(&dir).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn disk_nack_keeps_sending() {
+        let dir = temp_dir("nack");
+        let queue = queue(Storage::Disk(dir.clone()), 100);
+        queue.append(record("job", 1));
+        let batch = queue.take(None).pop().unwrap();
+        let file = batch.file.clone().unwrap();
+        assert_eq!(file.extension().unwrap(), "sending");
+        queue.nack(batch);
+        assert!(file.exists());
+        // more results of the job go to a new file, both are taken in the next round
+        queue.append(record("job", 2));
+        let mut batches = queue.take(Some("job"));
+        batches.sort_by_key(|batch| times(batch)[0]);
+        assert_eq!(
+            batches.iter().map(times).collect::<Vec<_>>(),
+            vec![vec![1], vec![2]]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn disk_corrupt_moved_aside() {
+        let dir = temp_dir("corrupt");
+        fs::write(dir.join("broken.jsonl"), "not json\n").unwrap();
+        let queue = queue(Storage::Disk(dir.clone()), 100);
+        assert!(queue.take(None).is_empty());
+        assert!(queued_files(&dir).is_empty());
+        let moved: Vec<_> = fs::read_dir(&dir).unwrap().flatten().collect();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].path().extension().unwrap(), "corrupt");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn disk_drop_oldest() {
+        let dir = temp_dir("drop");
+        let queue = queue(Storage::Disk(dir.clone()), 2);
+        queue.append(record("job", 1));
+        queue.append(record("job", 2));
+        // the full file goes as a whole to make room
+        queue.append(record("job", 3));
+        assert_eq!(*queue.len.lock().unwrap(), 1);
+        let batches = queue.take(None);
+        assert_eq!(times(&batches[0]), vec![3]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn memory_take_nack() {
+        let queue = queue(Storage::Memory(HashMap::new()), 100);
+        queue.append(record("a", 1));
+        queue.append(record("b", 2));
+        let batch = queue.take(Some("a")).pop().unwrap();
+        assert_eq!(times(&batch), vec![1]);
+        queue.append(record("a", 3));
+        // undelivered results go back in front of the newer ones
+        queue.nack(batch);
+        let batch = queue.take(Some("a")).pop().unwrap();
+        assert_eq!(times(&batch), vec![1, 3]);
+        queue.ack(batch);
+        assert_eq!(*queue.len.lock().unwrap(), 1);
+        assert!(queue.take(Some("a")).is_empty());
+    }
+
+    #[test]
+    fn memory_drop_oldest() {
+        let queue = queue(Storage::Memory(HashMap::new()), 2);
+        queue.append(record("a", 1));
+        queue.append(record("b", 2));
+        queue.append(record("c", 3));
+        assert_eq!(*queue.len.lock().unwrap(), 2);
+        let mut storage = Storage::Memory(HashMap::new());
+        assert_eq!(drop_oldest(&mut storage), None);
+        let mut ids: Vec<String> = queue.take(None).into_iter().map(|b| b.job_id).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["b", "c"]);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::app::run_job;
+use crate::job::Job;
+use crate::metrics;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio_cron_scheduler::{Job as CronJob, JobScheduler};
+use tracing::{debug, info};
+
+/// A recurring probe configured on the agent, like `http https://example.com every 30s`.
+#[derive(Clone)]
+pub struct Probe {
+    kind: &'static str,
+    target: String,
+    interval: Duration,
+}
+
+fn parse_interval(s: &str) -> Option<Duration> {
+    let unit_at = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let value: u64 = s[..unit_at].parse().ok()?;
+    let secs = match &s[unit_at..] {
+        "" | "s" => value,
+        "m" => value.checked_mul(60)?,
+        "h" => value.checked_mul(3600)?,
+        _ => return None,
+    };
+    if secs == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(secs))
+}
+
+impl Probe {
+    pub fn parse(spec: &str) -> Result<Probe, String> {
+        let parts: Vec<&str> = spec.split_whitespace().collect();
+        if parts.len() != 4 || parts[2] != "every" {
+            return Err(format!(
+                "invalid probe `{}`, expect `<type> <target> every <interval>`",
+                spec
+            ));
+        }
+        let kind = match parts[0] {
+            "ping" => "ping",
+            "tcping" => "tcping",
+            "http" => "http",
+            "dns" => "dns",
+            other => return Err(format!("unsupported probe type `{}`", other)),
+        };
+        let interval = parse_interval(parts[3])
+            .ok_or_else(|| format!("invalid probe interval `{}`", parts[3]))?;
+        Ok(Probe {
+            kind,
+            target: parts[1].to_string(),
+            interval,
+        })
+    }
+
+    fn request(&self) -> Value {
+        match self.kind {
+            "http" => json!({ "url": self.target }),
+            "dns" => json!({ "domain": self.target, "type": "A" }),
+            _ => json!({ "host": self.target, "single": true }),
+        }
+    }
+
+    async fn run(self) {
+        debug!("run scheduled {} probe to {}", self.kind, self.target);
+        let id = format!("probe-{}-{}", self.kind, self.target);
+        let (job, results) = Job::local(self.kind, Some(id));
+        let data = self.request();
+        run_job(job, data).await;
+        let results = results.lock().unwrap();
+        let result = results.last().cloned().unwrap_or(Value::Null);
+        let status = result["status"].as_u64();
+        let success = result.is_object()
+            && result.get("error").is_none()
+            && status.map_or(true, |status| (200..400).contains(&status));
+        metrics::observe_scheduled_probe(
+            self.kind,
+            &self.target,
+            success,
+            result["duration"].as_f64().map(|ms| ms / 1000.0),
+            status,
+        );
+    }
+}
+
+pub async fn add_probes(
+    sched: &JobScheduler,
+    specs: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for spec in specs {
+        let probe = Probe::parse(spec)?;
+        info!(
+            "schedule {} probe to {} every {}s",
+            probe.kind,
+            probe.target,
+            probe.interval.as_secs()
+        );
+        sched
+            .add(CronJob::new_repeated_async(
+                probe.interval,
+                move |_uuid, _l| Box::pin(probe.clone().run()),
+            )?)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_interval("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_interval("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_interval("2h"), Some(Duration::from_secs(7200)));
+    }
+
+    #[test]
+    fn invalid_intervals() {
+        for interval in [
+            "",
+            "0",
+            "0s",
+            "0m",
+            "s",
+            "-5s",
+            "1.5m",
+            "10d",
+            "5 m",
+            "99999999999999999h",
+        ] {
+            assert_eq!(parse_interval(interval), None, "{}", interval);
+        }
+    }
+
+    #[test]
+    fn probes() {
+        let probe = Probe::parse("http https://example.com every 30s").unwrap();
+        assert_eq!(probe.kind, "http");
+        assert_eq!(probe.target, "https://example.com");
+        assert_eq!(probe.interval, Duration::from_secs(30));
+        let probe = Probe::parse("  tcping   example.com:443 every 1m ").unwrap();
+        assert_eq!(
+            (probe.kind, probe.interval),
+            ("tcping", Duration::from_secs(60))
+        );
+        assert_eq!(
+            Probe::parse("dns example.com every 1h").unwrap().kind,
+            "dns"
+        );
+        assert_eq!(Probe::parse("ping 1.1.1.1 every 10").unwrap().kind, "ping");
+    }
+
+    #[test]
+    fn invalid_probes() {
+        for spec in [
+            "",
+            "http https://example.com",
+            "http https://example.com every",
+            "http https://example.com each 30s",
+            "http https://example.com every 30s extra",
+            "http https://example.com every 0s",
+            "http https://example.com every soon",
+        ] {
+            assert!(Probe::parse(spec).is_err(), "{}", spec);
+        }
+        let err = Probe::parse("mtr example.com every 30s").err().unwrap();
+        assert!(err.contains("unsupported probe type `mtr`"));
+    }
+}
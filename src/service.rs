@@ -0,0 +1,624 @@
+use crate::tls;
+use rustls::pki_types::ServerName;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::parse_x509_certificate;
+
+/// Greetings longer than this are cut, nobody sends a useful banner that long.
+const MAX_LINE: usize = 1024;
+/// Lines of a multi-line reply read before giving up.
+const MAX_LINES: usize = 64;
+/// Largest binary greeting or redis INFO reply read.
+const MAX_REPLY: usize = 64 * 1024;
+/// Length 8 and the magic code 80877103 asking the server whether it supports TLS.
+const POSTGRES_SSL_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+/// Capability flag of servers accepting TLS.
+const MYSQL_CLIENT_SSL: u16 = 0x0800;
+
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    Ssh,
+    Smtp,
+    Ftp,
+    Imap,
+    Pop3,
+    Redis,
+    Postgres,
+    Mysql,
+    Memcached,
+}
+
+impl Protocol {
+    pub fn parse(name: &str) -> Option<Protocol> {
+        match name {
+            "ssh" => Some(Protocol::Ssh),
+            "smtp" => Some(Protocol::Smtp),
+            "ftp" => Some(Protocol::Ftp),
+            "imap" => Some(Protocol::Imap),
+            "pop3" => Some(Protocol::Pop3),
+            "redis" => Some(Protocol::Redis),
+            "postgres" => Some(Protocol::Postgres),
+            "mysql" => Some(Protocol::Mysql),
+            "memcached" => Some(Protocol::Memcached),
+            _ => None,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Protocol::Ssh => 22,
+            Protocol::Smtp => 25,
+            Protocol::Ftp => 21,
+            Protocol::Imap => 143,
+            Protocol::Pop3 => 110,
+            Protocol::Redis => 6379,
+            Protocol::Postgres => 5432,
+            Protocol::Mysql => 3306,
+            Protocol::Memcached => 11211,
+        }
+    }
+}
+
+pub enum Failure {
+    Io(io::Error),
+    /// The server answered with something that isn't the expected greeting.
+    BadGreeting(String),
+    /// STARTTLS was refused or the handshake after it failed.
+    StartTls(String),
+    /// The handshake of a connection wrapped in TLS from the start failed.
+    Tls(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Io(e) => write!(f, "{}", e),
+            Failure::BadGreeting(line) => write!(f, "unexpected greeting: {}", line),
+            Failure::StartTls(e) => write!(f, "starttls failed: {}", e),
+            Failure::Tls(e) => write!(f, "tls handshake failed: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Self {
+        Failure::Io(e)
+    }
+}
+
+pub struct Certificate {
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    /// Unix timestamp.
+    pub not_before: i64,
+    /// Unix timestamp.
+    pub not_after: i64,
+    pub dns_names: Vec<String>,
+}
+
+pub struct Tls {
+    pub version: String,
+    pub cipher: String,
+    pub duration: Duration,
+    pub certificate: Option<Certificate>,
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub banner: Option<String>,
+    /// Server version, when the greeting tells it.
+    pub version: Option<String>,
+    /// Reply code of the greeting for SMTP and FTP.
+    pub code: Option<u16>,
+    pub greeting_duration: Duration,
+    /// Extensions announced in the SMTP EHLO reply.
+    pub extensions: Vec<String>,
+    /// Whether the server offers TLS, for protocols that announce it.
+    pub tls_supported: Option<bool>,
+    pub tls: Option<Tls>,
+}
+
+pub struct Options<'a> {
+    /// Name sent with EHLO.
+    pub ehlo: &'a str,
+    pub starttls: bool,
+    /// Wraps Redis and Memcached connections in TLS before the greeting.
+    pub tls: bool,
+    pub insecure: bool,
+    /// Name the certificate is checked against after STARTTLS or with `tls`.
+    pub server_name: &'a str,
+}
+
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> io::Result<String> {
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+/// Reads an SMTP or FTP reply, `220-` lines continue until the `220 ` one.
+async fn read_reply<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+) -> Result<(u16, Vec<String>), Failure> {
+    let mut lines = Vec::new();
+    while lines.len() < MAX_LINES {
+        let line = read_line(reader).await?;
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| Failure::BadGreeting(line.clone()))?;
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line.get(4..).unwrap_or_default().to_string());
+        if last {
+            return Ok((code, lines));
+        }
+    }
+    Err(Failure::BadGreeting(lines.join("\n")))
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, command: &str) -> io::Result<()> {
+    writer
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .await
+}
+
+fn certificate(der: &[u8]) -> Option<Certificate> {
+    let (_, cert) = parse_x509_certificate(der).ok()?;
+    let dns_names = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Some(Certificate {
+        subject: cert.subject().to_string(),
+        issuer: cert.issuer().to_string(),
+        serial: cert.raw_serial_as_string(),
+        not_before: cert.validity().not_before.timestamp(),
+        not_after: cert.validity().not_after.timestamp(),
+        dns_names,
+    })
+}
+
+async fn handshake(
+    stream: TcpStream,
+    options: &Options<'_>,
+) -> Result<(TlsStream<TcpStream>, Tls), String> {
+    let config = tls::client_config(options.insecure).map_err(|e| e.to_string())?;
+    let name = ServerName::try_from(options.server_name.to_string()).map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(name, stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    let duration = start.elapsed();
+    let (_, conn) = stream.get_ref();
+    let res = Tls {
+        version: conn
+            .protocol_version()
+            .map(|v| format!("{:?}", v))
+            .unwrap_or_default(),
+        cipher: conn
+            .negotiated_cipher_suite()
+            .map(|s| format!("{:?}", s.suite()))
+            .unwrap_or_default(),
+        duration,
+        certificate: conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| certificate(cert)),
+    };
+    Ok((stream, res))
+}
+
+async fn starttls(stream: TcpStream, options: &Options<'_>) -> Result<Tls, Failure> {
+    let (mut stream, tls) = handshake(stream, options)
+        .await
+        .map_err(Failure::StartTls)?;
+    let _ = send(&mut stream, "QUIT").await;
+    Ok(tls)
+}
+
+/// Reads the redis version from `INFO server`, servers requiring auth refuse it.
+async fn redis_version<R: AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut BufReader<R>,
+) -> Result<Option<String>, Failure> {
+    send(reader.get_mut(), "INFO server").await?;
+    let line = read_line(reader).await?;
+    let len = match line
+        .strip_prefix('$')
+        .and_then(|len| len.parse::<usize>().ok())
+    {
+        Some(len) if len <= MAX_REPLY => len,
+        _ => return Ok(None),
+    };
+    let mut info = vec![0u8; len + 2];
+    reader.read_exact(&mut info).await?;
+    Ok(String::from_utf8_lossy(&info)
+        .lines()
+        .find_map(|line| line.strip_prefix("redis_version:"))
+        .map(|version| version.to_string()))
+}
+
+/// Parses the MySQL initial handshake packet into the server version and whether it supports TLS.
+fn mysql_handshake(payload: &[u8]) -> Result<(String, bool), Failure> {
+    match payload.first() {
+        Some(10) => {}
+        // error packet, e.g. the host isn't allowed to connect
+        Some(0xff) => {
+            let message = payload.get(3..).unwrap_or_default();
+            return Err(Failure::BadGreeting(
+                String::from_utf8_lossy(message).to_string(),
+            ));
+        }
+        _ => return Err(Failure::BadGreeting(hex::encode(payload))),
+    }
+    let end = payload[1..]
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| Failure::BadGreeting(hex::encode(payload)))?
+        + 1;
+    let version = String::from_utf8_lossy(&payload[1..end]).to_string();
+    // connection id, first part of the auth data and a filler come before the capability flags
+    let flags = end + 1 + 4 + 8 + 1;
+    let capabilities = payload
+        .get(flags..flags + 2)
+        .map_or(0, |flags| u16::from_le_bytes([flags[0], flags[1]]));
+    Ok((version, capabilities & MYSQL_CLIENT_SSL != 0))
+}
+
+fn quit(protocol: Protocol) -> Option<&'static str> {
+    match protocol {
+        Protocol::Smtp | Protocol::Ftp | Protocol::Pop3 | Protocol::Redis => Some("QUIT"),
+        Protocol::Imap => Some("a1 LOGOUT"),
+        Protocol::Memcached => Some("quit"),
+        Protocol::Ssh | Protocol::Postgres | Protocol::Mysql => None,
+    }
+}
+
+/// Sends the PING or version request of Redis and Memcached, which don't greet first.
+async fn request_greeting<R: AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut BufReader<R>,
+    protocol: Protocol,
+    start: Instant,
+) -> Result<Report, Failure> {
+    if let Protocol::Memcached = protocol {
+        send(reader.get_mut(), "version").await?;
+        let line = read_line(reader).await?;
+        let version = match line.strip_prefix("VERSION ") {
+            Some(version) => version.to_string(),
+            None => return Err(Failure::BadGreeting(line)),
+        };
+        return Ok(Report {
+            banner: Some(line),
+            version: Some(version),
+            greeting_duration: start.elapsed(),
+            ..Default::default()
+        });
+    }
+    send(reader.get_mut(), "PING").await?;
+    let line = read_line(reader).await?;
+    let greeting_duration = start.elapsed();
+    // an error like NOAUTH still shows the server is answering
+    if !line.starts_with('+') && !line.starts_with('-') {
+        return Err(Failure::BadGreeting(line));
+    }
+    let version = if line == "+PONG" {
+        redis_version(reader).await?
+    } else {
+        None
+    };
+    Ok(Report {
+        banner: Some(line),
+        version,
+        greeting_duration,
+        ..Default::default()
+    })
+}
+
+/// Completes the greeting of `protocol` on a connected stream, no credentials are sent.
+pub async fn check(
+    stream: TcpStream,
+    protocol: Protocol,
+    options: &Options<'_>,
+) -> Result<Report, Failure> {
+    let start = Instant::now();
+    let mut reader = BufReader::new(stream);
+    let mut report = match protocol {
+        Protocol::Ssh => {
+            // servers may send other lines before the identification string
+            let mut banner = None;
+            for _ in 0..MAX_LINES {
+                let line = read_line(&mut reader).await?;
+                if line.starts_with("SSH-") {
+                    banner = Some(line);
+                    break;
+                }
+            }
+            let banner = banner.ok_or_else(|| Failure::BadGreeting(String::new()))?;
+            Report {
+                // SSH-protoversion-softwareversion comments
+                version: banner.splitn(3, '-').nth(2).map(|v| v.to_string()),
+                banner: Some(banner),
+                greeting_duration: start.elapsed(),
+                ..Default::default()
+            }
+        }
+        Protocol::Smtp | Protocol::Ftp => {
+            let (code, lines) = read_reply(&mut reader).await?;
+            if code != 220 {
+                return Err(Failure::BadGreeting(format!(
+                    "{} {}",
+                    code,
+                    lines.join(" ")
+                )));
+            }
+            Report {
+                banner: Some(lines.join("\n")),
+                code: Some(code),
+                greeting_duration: start.elapsed(),
+                ..Default::default()
+            }
+        }
+        Protocol::Imap | Protocol::Pop3 => {
+            let line = read_line(&mut reader).await?;
+            let ok = match protocol {
+                Protocol::Imap => line.starts_with("* OK") || line.starts_with("* PREAUTH"),
+                _ => line.starts_with("+OK"),
+            };
+            if !ok {
+                return Err(Failure::BadGreeting(line));
+            }
+            Report {
+                banner: Some(line),
+                greeting_duration: start.elapsed(),
+                ..Default::default()
+            }
+        }
+        Protocol::Redis | Protocol::Memcached if options.tls => {
+            let (stream, tls) = handshake(reader.into_inner(), options)
+                .await
+                .map_err(Failure::Tls)?;
+            let mut reader = BufReader::new(stream);
+            let mut report = request_greeting(&mut reader, protocol, Instant::now()).await?;
+            report.tls_supported = Some(true);
+            report.tls = Some(tls);
+            if let Some(quit) = quit(protocol) {
+                let _ = send(reader.get_mut(), quit).await;
+            }
+            return Ok(report);
+        }
+        Protocol::Redis | Protocol::Memcached => {
+            request_greeting(&mut reader, protocol, start).await?
+        }
+        Protocol::Postgres => {
+            reader.get_mut().write_all(&POSTGRES_SSL_REQUEST).await?;
+            let mut answer = [0u8; 1];
+            reader.read_exact(&mut answer).await?;
+            // servers too old to know the request answer with an error message
+            let tls_supported = match answer[0] {
+                b'S' => true,
+                b'N' | b'E' => false,
+                other => return Err(Failure::BadGreeting(format!("{:#04x}", other))),
+            };
+            Report {
+                greeting_duration: start.elapsed(),
+                tls_supported: Some(tls_supported),
+                ..Default::default()
+            }
+        }
+        Protocol::Mysql => {
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header).await?;
+            let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+            if len > MAX_REPLY {
+                return Err(Failure::BadGreeting(hex::encode(header)));
+            }
+            let mut payload = vec![0u8; len];
+            reader.read_exact(&mut payload).await?;
+            let (version, tls_supported) = mysql_handshake(&payload)?;
+            Report {
+                version: Some(version),
+                greeting_duration: start.elapsed(),
+                tls_supported: Some(tls_supported),
+                ..Default::default()
+            }
+        }
+    };
+    if let Protocol::Smtp = protocol {
+        send(reader.get_mut(), &format!("EHLO {}", options.ehlo)).await?;
+        let (code, lines) = read_reply(&mut reader).await?;
+        if code != 250 {
+            return Err(Failure::BadGreeting(format!(
+                "{} {}",
+                code,
+                lines.join(" ")
+            )));
+        }
+        // the first line is the server's hostname
+        report.extensions = lines.into_iter().skip(1).collect();
+        let supported = report
+            .extensions
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case("STARTTLS"));
+        report.tls_supported = Some(supported);
+        if options.starttls {
+            if !supported {
+                return Err(Failure::StartTls("not announced".to_string()));
+            }
+            send(reader.get_mut(), "STARTTLS").await?;
+            let (code, lines) = read_reply(&mut reader).await?;
+            if code != 220 {
+                return Err(Failure::StartTls(format!("{} {}", code, lines.join(" "))));
+            }
+            report.tls = Some(starttls(reader.into_inner(), options).await?);
+            return Ok(report);
+        }
+    }
+    if let Some(quit) = quit(protocol) {
+        let _ = send(reader.get_mut(), quit).await;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Handshake v10 of a MySQL 8 server up to the lower capability flags.
+    fn handshake(version: &str, capabilities: u16) -> Vec<u8> {
+        let mut payload = vec![10];
+        payload.extend_from_slice(version.as_bytes());
+        payload.push(0);
+        payload.extend_from_slice(&42u32.to_le_bytes());
+        payload.extend_from_slice(b"abcdefgh");
+        payload.push(0);
+        payload.extend_from_slice(&capabilities.to_le_bytes());
+        payload.extend_from_slice(&[0xff, 0x02, 0x00]);
+        payload
+    }
+
+    #[test]
+    fn mysql_version_and_tls() {
+        let payload = handshake("8.0.36", 0xffff);
+        assert!(matches!(
+            mysql_handshake(&payload),
+            Ok((version, true)) if version == "8.0.36"
+        ));
+        let payload = handshake("5.7.44-log", 0xf7ff);
+        assert!(matches!(
+            mysql_handshake(&payload),
+            Ok((version, false)) if version == "5.7.44-log"
+        ));
+    }
+
+    #[test]
+    fn mysql_without_capabilities() {
+        let payload = handshake("8.0.36", 0xffff);
+        let short = &payload[..payload.len() - 5];
+        assert!(matches!(mysql_handshake(short), Ok((_, false))));
+    }
+
+    #[test]
+    fn mysql_error_packet() {
+        let mut payload = vec![0xff, 0x6a, 0x04];
+        payload.extend_from_slice(b"Host '192.0.2.1' is not allowed to connect");
+        assert!(matches!(
+            mysql_handshake(&payload),
+            Err(Failure::BadGreeting(message)) if message.starts_with("Host '192.0.2.1'")
+        ));
+    }
+
+    #[test]
+    fn mysql_bad_greetings() {
+        assert!(matches!(mysql_handshake(&[]), Err(Failure::BadGreeting(_))));
+        assert!(matches!(
+            mysql_handshake(b"\x09old"),
+            Err(Failure::BadGreeting(_))
+        ));
+        // the version is never terminated
+        assert!(matches!(
+            mysql_handshake(b"\x0a8.0.36"),
+            Err(Failure::BadGreeting(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn ssh_banner_after_other_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"welcome\r\nSSH-2.0-OpenSSH_9.6 Ubuntu\r\n")
+                .await
+                .unwrap();
+        });
+        let options = Options {
+            ehlo: "nodecook-agent",
+            starttls: false,
+            tls: false,
+            insecure: false,
+            server_name: "localhost",
+        };
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let Ok(report) = check(stream, Protocol::Ssh, &options).await else {
+            panic!("ssh greeting not recognized");
+        };
+        assert_eq!(report.banner.as_deref(), Some("SSH-2.0-OpenSSH_9.6 Ubuntu"));
+        assert_eq!(report.version.as_deref(), Some("OpenSSH_9.6 Ubuntu"));
+    }
+
+    #[tokio::test]
+    async fn redis_version_after_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            assert_eq!(read_line(&mut reader).await.unwrap(), "PING");
+            reader.get_mut().write_all(b"+PONG\r\n").await.unwrap();
+            assert_eq!(read_line(&mut reader).await.unwrap(), "INFO server");
+            let info = "# Server\r\nredis_version:7.2.4\r\n";
+            let reply = format!("${}\r\n{}\r\n", info.len(), info);
+            reader.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        });
+        let options = Options {
+            ehlo: "nodecook-agent",
+            starttls: false,
+            tls: false,
+            insecure: false,
+            server_name: "localhost",
+        };
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let Ok(report) = check(stream, Protocol::Redis, &options).await else {
+            panic!("redis greeting not recognized");
+        };
+        assert_eq!(report.banner.as_deref(), Some("+PONG"));
+        assert_eq!(report.version.as_deref(), Some("7.2.4"));
+        assert!(report.tls_supported.is_none());
+    }
+
+    #[tokio::test]
+    async fn tls_to_plain_memcached() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // memcached answers the ClientHello with an error and closes
+            stream.write_all(b"ERROR\r\n").await.unwrap();
+        });
+        let options = Options {
+            ehlo: "nodecook-agent",
+            starttls: false,
+            tls: true,
+            insecure: true,
+            server_name: "localhost",
+        };
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(matches!(
+            check(stream, Protocol::Memcached, &options).await,
+            Err(Failure::Tls(_))
+        ));
+    }
+}
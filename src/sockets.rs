@@ -0,0 +1,195 @@
+use serde_json::Value;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+static DEFAULT: OnceLock<Source> = OnceLock::new();
+
+/// Where probes leave the agent from, multi-homed hosts pick an uplink with it.
+#[derive(Clone, Default)]
+pub struct Source {
+    pub ip: Option<IpAddr>,
+    /// Interface the sockets are bound to with `SO_BINDTODEVICE`.
+    pub interface: Option<String>,
+}
+
+/// Sets the source of probes whose request doesn't choose one.
+pub fn init(ip: Option<IpAddr>, interface: Option<String>) {
+    DEFAULT.get_or_init(|| Source { ip, interface });
+}
+
+fn unspecified(target: IpAddr) -> IpAddr {
+    match target {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+impl Source {
+    /// The `source_ip` and `interface` of a request over the agent defaults, `None` if the
+    /// source ip is not an ip. A `vrf` device is bound like an interface, which scopes the
+    /// sockets to the routing table of the VRF, so a request can't set both.
+    pub fn from_request(data: &Value) -> Option<Source> {
+        if data["interface"].is_string() && data["vrf"].is_string() {
+            return None;
+        }
+        let default = DEFAULT.get().cloned().unwrap_or_default();
+        let ip = match data["source_ip"].as_str() {
+            Some(ip) => Some(ip.parse().ok()?),
+            None => default.ip,
+        };
+        let interface = data["interface"]
+            .as_str()
+            .or(data["vrf"].as_str())
+            .map(|interface| interface.to_string())
+            .or(default.interface);
+        Some(Source { ip, interface })
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.ip.is_none() && self.interface.is_none()
+    }
+
+    /// Local address to send to `target` from, the port is left to the kernel.
+    pub fn local(&self, target: IpAddr) -> io::Result<SocketAddr> {
+        match self.ip {
+            Some(ip) if ip.is_ipv4() != target.is_ipv4() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("source {} can't reach {}", ip, target),
+            )),
+            Some(ip) => Ok(SocketAddr::new(ip, 0)),
+            None => Ok(SocketAddr::new(unspecified(target), 0)),
+        }
+    }
+
+    /// Local ip for clients that can only bind an address, the address of the interface
+    /// stands in for binding to the interface itself.
+    pub fn local_ip(&self, target: IpAddr) -> io::Result<IpAddr> {
+        if self.ip.is_none() {
+            if let Some(interface) = &self.interface {
+                return interface_ip(interface, target.is_ipv4());
+            }
+        }
+        Ok(self.local(target)?.ip())
+    }
+
+    /// Binds `sock` to the interface and source ip before it connects to or sends to `target`.
+    pub fn apply(&self, sock: &Socket, target: IpAddr) -> io::Result<()> {
+        if let Some(interface) = &self.interface {
+            bind_device(sock, interface)?;
+        }
+        sock.bind(&SockAddr::from(self.local(target)?))
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(sock: &Socket, interface: &str) -> io::Result<()> {
+    sock.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_sock: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is not supported on this platform",
+    ))
+}
+
+/// First address of the wanted family assigned to `interface`.
+#[cfg(unix)]
+fn interface_ip(interface: &str, is_ipv4: bool) -> io::Result<IpAddr> {
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut found = None;
+    let mut cur = addrs;
+    while !cur.is_null() && found.is_none() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        let name = unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) };
+        if ifa.ifa_addr.is_null() || name.to_bytes() != interface.as_bytes() {
+            continue;
+        }
+        let family = unsafe { (*ifa.ifa_addr).sa_family } as i32;
+        found = match family {
+            libc::AF_INET if is_ipv4 => {
+                let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    addr.sin_addr.s_addr,
+                ))))
+            }
+            libc::AF_INET6 if !is_ipv4 => {
+                let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+            }
+            _ => None,
+        };
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    found.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no address of the target family on {}", interface),
+        )
+    })
+}
+
+#[cfg(not(unix))]
+fn interface_ip(_interface: &str, _is_ipv4: bool) -> io::Result<IpAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "looking up interface addresses is not supported on this platform",
+    ))
+}
+
+fn socket(source: &Source, target: IpAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    let sock = Socket::new(
+        Domain::for_address(SocketAddr::new(target, 0)),
+        kind,
+        Some(protocol),
+    )?;
+    sock.set_nonblocking(true)?;
+    source.apply(&sock, target)?;
+    Ok(sock)
+}
+
+pub async fn tcp_connect(source: &Source, addr: SocketAddr) -> io::Result<TcpStream> {
+    let sock = socket(source, addr.ip(), Type::STREAM, Protocol::TCP)?;
+    TcpSocket::from_std_stream(sock.into()).connect(addr).await
+}
+
+/// A UDP socket from `source`, bound but not connected.
+pub fn udp_bind(source: &Source, target: IpAddr) -> io::Result<std::net::UdpSocket> {
+    Ok(socket(source, target, Type::DGRAM, Protocol::UDP)?.into())
+}
+
+pub async fn udp_connect(source: &Source, addr: SocketAddr) -> io::Result<UdpSocket> {
+    let sock = UdpSocket::from_std(udp_bind(source, addr.ip())?)?;
+    sock.connect(addr).await?;
+    Ok(sock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn request_source() {
+        let source = Source::from_request(&json!({ "source_ip": "192.0.2.1" })).unwrap();
+        assert_eq!(source.ip, Some("192.0.2.1".parse().unwrap()));
+        let source = Source::from_request(&json!({ "vrf": "blue" })).unwrap();
+        assert_eq!(source.interface.as_deref(), Some("blue"));
+        let source = Source::from_request(&json!({ "interface": "eth1" })).unwrap();
+        assert_eq!(source.interface.as_deref(), Some("eth1"));
+    }
+
+    #[test]
+    fn request_source_rejects() {
+        assert!(Source::from_request(&json!({ "source_ip": "eth0" })).is_none());
+        assert!(Source::from_request(&json!({ "interface": "eth1", "vrf": "blue" })).is_none());
+    }
+}
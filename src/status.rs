@@ -0,0 +1,169 @@
+use crate::capability;
+use crate::constant::VERSION;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Errors older than this are dropped from the status report.
+const ERROR_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+static STARTED_AT: LazyLock<Instant> = LazyLock::new(Instant::now);
+static RUNNING_JOBS: LazyLock<Mutex<HashMap<&'static str, u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static RECENT_ERRORS: LazyLock<Mutex<VecDeque<RecordedError>>> =
+    LazyLock::new(|| Mutex::new(VecDeque::new()));
+static REGISTRATIONS: LazyLock<Mutex<HashMap<&'static str, Registration>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static TUNNEL: Mutex<Option<Tunnel>> = Mutex::new(None);
+
+struct RecordedError {
+    at: Instant,
+    job: &'static str,
+    error: String,
+}
+
+#[derive(Default)]
+struct Registration {
+    registered: bool,
+    last_attempt: Option<u64>,
+    last_success: Option<u64>,
+}
+
+struct Tunnel {
+    connected: bool,
+    last_connected: Option<u64>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Starts the uptime clock, call it once as early as possible.
+pub fn init() {
+    LazyLock::force(&STARTED_AT);
+}
+
+pub fn job_started(job: &'static str) {
+    *RUNNING_JOBS.lock().unwrap().entry(job).or_insert(0) += 1;
+}
+
+pub fn job_finished(job: &'static str) {
+    let mut jobs = RUNNING_JOBS.lock().unwrap();
+    if let Some(count) = jobs.get_mut(job) {
+        *count = count.saturating_sub(1);
+    }
+}
+
+pub fn record_error(job: &'static str, error: &Value) {
+    let error = match error {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    let mut errors = RECENT_ERRORS.lock().unwrap();
+    prune(&mut errors);
+    errors.push_back(RecordedError {
+        at: Instant::now(),
+        job,
+        error,
+    });
+}
+
+pub fn record_registration(ip_type: &'static str, success: bool) {
+    let now = unix_now();
+    let mut registrations = REGISTRATIONS.lock().unwrap();
+    let registration = registrations.entry(ip_type).or_default();
+    registration.registered = success;
+    registration.last_attempt = Some(now);
+    if success {
+        registration.last_success = Some(now);
+    }
+}
+
+pub fn record_tunnel(connected: bool) {
+    let mut tunnel = TUNNEL.lock().unwrap();
+    let last_connected = tunnel.as_ref().and_then(|t| t.last_connected);
+    *tunnel = Some(Tunnel {
+        connected,
+        last_connected: if connected {
+            Some(unix_now())
+        } else {
+            last_connected
+        },
+    });
+}
+
+fn prune(errors: &mut VecDeque<RecordedError>) {
+    while let Some(front) = errors.front() {
+        if front.at.elapsed() < ERROR_WINDOW {
+            break;
+        }
+        errors.pop_front();
+    }
+}
+
+fn registrations() -> Value {
+    let registrations = REGISTRATIONS.lock().unwrap();
+    let mut res = Map::new();
+    for ip_type in ["ipv4", "ipv6"] {
+        let value = match registrations.get(ip_type) {
+            Some(r) => json!({
+                "registered": r.registered,
+                "last_attempt": r.last_attempt,
+                "last_success": r.last_success,
+            }),
+            None => json!({
+                "registered": false,
+                "last_attempt": None::<u64>,
+                "last_success": None::<u64>,
+            }),
+        };
+        res.insert(ip_type.to_string(), value);
+    }
+    Value::Object(res)
+}
+
+fn errors() -> Value {
+    let mut errors = RECENT_ERRORS.lock().unwrap();
+    prune(&mut errors);
+    let mut by_job: HashMap<&str, u64> = HashMap::new();
+    let mut by_error: HashMap<&str, u64> = HashMap::new();
+    for e in errors.iter() {
+        *by_job.entry(e.job).or_insert(0) += 1;
+        *by_error.entry(e.error.as_str()).or_insert(0) += 1;
+    }
+    json!({
+        "window": ERROR_WINDOW.as_secs(),
+        "total": errors.len(),
+        "by_job": by_job,
+        "by_error": by_error,
+    })
+}
+
+pub fn report() -> Value {
+    let jobs: HashMap<&str, u64> = RUNNING_JOBS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, count)| **count > 0)
+        .map(|(job, count)| (*job, *count))
+        .collect();
+    let tunnel = TUNNEL.lock().unwrap().as_ref().map(|t| {
+        json!({
+            "connected": t.connected,
+            "last_connected": t.last_connected,
+        })
+    });
+    json!({
+        "version": VERSION,
+        "uptime": STARTED_AT.elapsed().as_secs(),
+        "registration": registrations(),
+        "tunnel": tunnel,
+        "jobs": jobs,
+        "errors": errors(),
+        "capabilities": capability::get(),
+    })
+}
@@ -0,0 +1,799 @@
+#[cfg(target_os = "linux")]
+use crate::errqueue::{self, Readiness};
+use crate::sockets::Source;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+/// Traceroute's first port, the destination port of every probe is offset by its sequence.
+pub const PORT: u16 = 33434;
+/// Most flows traced at once to enumerate ECMP paths.
+pub const MAX_FLOWS: u16 = 16;
+/// UDP payload of a probe, the first two bytes steer the checksum of Paris probes.
+const PAYLOAD: usize = 32;
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP6_DST_UNREACH: u8 = 1;
+const ICMP6_TIME_EXCEEDED: u8 = 3;
+/// Original datagram length assumed by implementations predating RFC 4884.
+const COMPAT_ORIGINAL_LENGTH: usize = 128;
+const EXTENSION_VERSION: u8 = 2;
+const CLASS_MPLS: u8 = 1;
+const CLASS_INTERFACE: u8 = 2;
+const IPPROTO_UDP: u8 = 17;
+
+#[derive(Clone, Copy)]
+pub struct Options {
+    pub first_hop: u8,
+    pub max_hops: u8,
+    /// How long to wait for the answer to a probe.
+    pub timeout: Duration,
+    /// Keep the flow of all probes the same like Paris traceroute does, load balancers then
+    /// send them down the same path. The flow sets the destination port, probes are told
+    /// apart by their UDP checksum instead.
+    pub flow: Option<u16>,
+}
+
+/// An entry of the MPLS label stack the probe carried when it expired, RFC 4950.
+pub struct MplsLabel {
+    pub label: u32,
+    pub tc: u8,
+    /// Bottom of the stack.
+    pub bottom: bool,
+    pub ttl: u8,
+}
+
+/// The interface a hop received the probe on or would have sent it out of, RFC 5837.
+pub struct Interface {
+    pub role: &'static str,
+    pub ifindex: Option<u32>,
+    pub addr: Option<IpAddr>,
+    pub name: Option<String>,
+    pub mtu: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct Extensions {
+    pub mpls: Vec<MplsLabel>,
+    pub interfaces: Vec<Interface>,
+}
+
+pub struct Hop {
+    /// TTL of the probe.
+    pub hop: u8,
+    pub addr: Option<IpAddr>,
+    pub rtt: Option<Duration>,
+    /// TTL of the answer, only known for ipv4.
+    pub reply_ttl: Option<u8>,
+    /// The answer came from the target, the trace is over.
+    pub reached: bool,
+    /// ICMP code of a "destination unreachable" sent by a hop on the way.
+    pub unreachable: Option<u8>,
+    pub extensions: Extensions,
+}
+
+struct Reply {
+    addr: IpAddr,
+    icmp_type: u8,
+    icmp_code: u8,
+    /// Destination port of the quoted probe.
+    port: u16,
+    /// UDP checksum of the quoted probe.
+    checksum: u16,
+    reply_ttl: Option<u8>,
+    extensions: Extensions,
+}
+
+impl Reply {
+    /// ICMP code of a "destination unreachable", the types overlap between ICMP and ICMPv6
+    /// and time exceeded is 3 in ICMPv6.
+    fn unreachable(&self) -> Option<u8> {
+        let unreach_type = if self.addr.is_ipv4() {
+            ICMP_DEST_UNREACH
+        } else {
+            ICMP6_DST_UNREACH
+        };
+        (self.icmp_type == unreach_type).then_some(self.icmp_code)
+    }
+}
+
+fn be16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+fn be32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn parse_mpls(mut payload: &[u8]) -> Vec<MplsLabel> {
+    let mut labels = Vec::new();
+    while payload.len() >= 4 {
+        let entry = be32(payload);
+        labels.push(MplsLabel {
+            label: entry >> 12,
+            tc: ((entry >> 9) & 0x07) as u8,
+            bottom: entry & 0x100 != 0,
+            ttl: (entry & 0xff) as u8,
+        });
+        payload = &payload[4..];
+    }
+    labels
+}
+
+fn parse_interface(c_type: u8, mut payload: &[u8]) -> Option<Interface> {
+    let role = match c_type >> 6 {
+        0 => "incoming",
+        1 => "sub_ip",
+        2 => "outgoing",
+        _ => "next_hop",
+    };
+    let mut interface = Interface {
+        role,
+        ifindex: None,
+        addr: None,
+        name: None,
+        mtu: None,
+    };
+    // the sub-objects follow in this order, each present when its bit is set
+    if c_type & 0x08 != 0 {
+        interface.ifindex = Some(be32(payload.get(..4)?));
+        payload = &payload[4..];
+    }
+    if c_type & 0x04 != 0 {
+        let afi = be16(payload.get(..2)?);
+        let (addr, len) = match afi {
+            1 => {
+                let octets: [u8; 4] = payload.get(4..8)?.try_into().ok()?;
+                (IpAddr::V4(Ipv4Addr::from(octets)), 8)
+            }
+            2 => {
+                let octets: [u8; 16] = payload.get(4..20)?.try_into().ok()?;
+                (IpAddr::V6(Ipv6Addr::from(octets)), 20)
+            }
+            _ => return None,
+        };
+        interface.addr = Some(addr);
+        payload = &payload[len..];
+    }
+    if c_type & 0x02 != 0 {
+        // the length octet counts itself and the padding
+        let len = *payload.first()? as usize;
+        let name = payload.get(1..len)?;
+        interface.name = Some(
+            String::from_utf8_lossy(name)
+                .trim_end_matches('\0')
+                .to_string(),
+        );
+        payload = &payload[len..];
+    }
+    if c_type & 0x01 != 0 {
+        interface.mtu = Some(be32(payload.get(..4)?));
+    }
+    Some(interface)
+}
+
+/// Parses an ICMP extension structure, RFC 4884.
+fn parse_extensions(buf: &[u8]) -> Option<Extensions> {
+    if buf.len() < 4 || buf[0] >> 4 != EXTENSION_VERSION {
+        return None;
+    }
+    let mut extensions = Extensions::default();
+    let mut objects = &buf[4..];
+    while objects.len() >= 4 {
+        let len = be16(objects) as usize;
+        if len < 4 || len > objects.len() {
+            break;
+        }
+        let (class, c_type, payload) = (objects[2], objects[3], &objects[4..len]);
+        match class {
+            CLASS_MPLS => extensions.mpls.extend(parse_mpls(payload)),
+            CLASS_INTERFACE => extensions
+                .interfaces
+                .extend(parse_interface(c_type, payload)),
+            _ => {}
+        }
+        objects = &objects[len..];
+    }
+    Some(extensions)
+}
+
+/// Finds the extensions after the original datagram quoted in an ICMP error.
+///
+/// `length` is the original datagram length from the ICMP header, 0 when the sender
+/// doesn't implement RFC 4884, the extensions may then still follow 128 bytes in.
+fn extensions(body: &[u8], length: usize) -> Extensions {
+    let offset = if length > 0 {
+        length
+    } else {
+        COMPAT_ORIGINAL_LENGTH
+    };
+    body.get(offset..)
+        .and_then(parse_extensions)
+        .unwrap_or_default()
+}
+
+fn parse_v4(packet: &[u8], target: Ipv4Addr, local_port: u16) -> Option<Reply> {
+    let ihl = ((packet.first()? & 0x0f) as usize) * 4;
+    let reply_ttl = *packet.get(8)?;
+    let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+    let icmp = packet.get(ihl..)?;
+    let (icmp_type, icmp_code) = (*icmp.first()?, *icmp.get(1)?);
+    if icmp_type != ICMP_TIME_EXCEEDED && icmp_type != ICMP_DEST_UNREACH {
+        return None;
+    }
+    let body = icmp.get(8..)?;
+    let quoted_ihl = ((body.first()? & 0x0f) as usize) * 4;
+    let quoted_dst: [u8; 4] = body.get(16..20)?.try_into().ok()?;
+    if body.get(9) != Some(&IPPROTO_UDP) || Ipv4Addr::from(quoted_dst) != target {
+        return None;
+    }
+    let udp = body.get(quoted_ihl..quoted_ihl + 8)?;
+    if be16(udp) != local_port {
+        return None;
+    }
+    Some(Reply {
+        addr: IpAddr::V4(Ipv4Addr::from(source)),
+        icmp_type,
+        icmp_code,
+        port: be16(&udp[2..]),
+        checksum: be16(&udp[6..]),
+        reply_ttl: Some(reply_ttl),
+        // length in 32-bit words
+        extensions: extensions(body, icmp[5] as usize * 4),
+    })
+}
+
+fn parse_v6(icmp: &[u8], source: Ipv6Addr, target: Ipv6Addr, local_port: u16) -> Option<Reply> {
+    let (icmp_type, icmp_code) = (*icmp.first()?, *icmp.get(1)?);
+    if icmp_type != ICMP6_TIME_EXCEEDED && icmp_type != ICMP6_DST_UNREACH {
+        return None;
+    }
+    let body = icmp.get(8..)?;
+    let quoted_dst: [u8; 16] = body.get(24..40)?.try_into().ok()?;
+    if body.get(6) != Some(&IPPROTO_UDP) || Ipv6Addr::from(quoted_dst) != target {
+        return None;
+    }
+    let udp = body.get(40..48)?;
+    if be16(udp) != local_port {
+        return None;
+    }
+    Some(Reply {
+        addr: IpAddr::V6(source),
+        icmp_type,
+        icmp_code,
+        port: be16(&udp[2..]),
+        checksum: be16(&udp[6..]),
+        reply_ttl: None,
+        // length in 64-bit words
+        extensions: extensions(body, icmp[4] as usize * 8),
+    })
+}
+
+/// Ones' complement sum of `buf` as 16-bit words, folded to 16 bits.
+fn ones_sum(buf: &[u8]) -> u16 {
+    let mut sum: u32 = buf
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn ones_add(a: u16, b: u16) -> u16 {
+    let sum = a as u32 + b as u32;
+    ((sum & 0xffff) + (sum >> 16)) as u16
+}
+
+/// Builds a payload that makes the kernel compute `checksum` for the UDP datagram.
+fn paris_payload(source: SocketAddr, target: SocketAddr, checksum: u16) -> [u8; PAYLOAD] {
+    let mut payload = [0u8; PAYLOAD];
+    let len = (8 + PAYLOAD) as u16;
+    let mut header = Vec::with_capacity(48);
+    match (source.ip(), target.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            header.extend_from_slice(&[0, IPPROTO_UDP]);
+            header.extend_from_slice(&len.to_be_bytes());
+        }
+        (src, dst) => {
+            let octets = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                IpAddr::V6(ip) => ip.octets(),
+            };
+            header.extend_from_slice(&octets(src));
+            header.extend_from_slice(&octets(dst));
+            header.extend_from_slice(&(len as u32).to_be_bytes());
+            header.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&target.port().to_be_bytes());
+    header.extend_from_slice(&len.to_be_bytes());
+    // checksum = !(sum + word), the payload is all zeros besides the word
+    let sum = ones_sum(&header);
+    let word = ones_add(!checksum, !sum);
+    payload[..2].copy_from_slice(&word.to_be_bytes());
+    payload
+}
+
+enum Receiver {
+    /// A raw ICMP socket, it sees the whole ICMP message including the extensions.
+    Raw(UdpSocket),
+    /// ICMP errors queued on the probe socket by `IP_RECVERR`, no privileges are needed but
+    /// only the type, code and sender of the message are known.
+    #[cfg(target_os = "linux")]
+    ErrQueue,
+}
+
+/// What identifies a probe in the ICMP error it triggers.
+struct Sent {
+    port: u16,
+    checksum: u16,
+    payload: [u8; PAYLOAD],
+}
+
+struct Tracer {
+    target: IpAddr,
+    probe: UdpSocket,
+    receiver: Receiver,
+    local_port: u16,
+    /// Destination and source of Paris probes, the socket is connected then.
+    flow: Option<(SocketAddr, SocketAddr)>,
+    buf: Vec<u8>,
+}
+
+impl Tracer {
+    fn new(target: IpAddr, flow: Option<u16>, source: &Source) -> io::Result<Tracer> {
+        let (domain, protocol) = match target {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let probe = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        source.apply(&probe, target)?;
+        let flow = match flow {
+            Some(flow) => {
+                let dst = SocketAddr::new(target, PORT.wrapping_add(flow));
+                probe.connect(&SockAddr::from(dst))?;
+                let src = probe.local_addr()?.as_socket();
+                src.map(|src| (dst, src))
+            }
+            None => None,
+        };
+        let local_port = probe
+            .local_addr()?
+            .as_socket()
+            .map_or(0, |addr| addr.port());
+        // only the recv calls are used, a raw socket reads like any datagram socket
+        let receiver = match Socket::new(domain, Type::RAW, Some(protocol)) {
+            Ok(icmp) => {
+                source.apply(&icmp, target)?;
+                Receiver::Raw(icmp.into())
+            }
+            #[cfg(target_os = "linux")]
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                errqueue::enable(probe.as_raw_fd(), target.is_ipv4())?;
+                Receiver::ErrQueue
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Tracer {
+            target,
+            probe: probe.into(),
+            receiver,
+            local_port,
+            flow,
+            buf: vec![0u8; 1500],
+        })
+    }
+
+    fn set_hops(&self, ttl: u8) -> io::Result<()> {
+        let sock = socket2::SockRef::from(&self.probe);
+        match self.target {
+            IpAddr::V4(_) => sock.set_ttl(ttl as u32),
+            IpAddr::V6(_) => sock.set_unicast_hops_v6(ttl as u32),
+        }
+    }
+
+    /// Waits for the answer to `sent`, answers to earlier probes that arrive late are skipped.
+    fn receive(&mut self, deadline: Instant, sent: &Sent) -> io::Result<Option<Reply>> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            let reply = match &self.receiver {
+                Receiver::Raw(icmp) => {
+                    icmp.set_read_timeout(Some(remaining))?;
+                    let (n, from) = match icmp.recv_from(&mut self.buf) {
+                        Ok(res) => res,
+                        Err(e)
+                            if e.kind() == io::ErrorKind::WouldBlock
+                                || e.kind() == io::ErrorKind::TimedOut =>
+                        {
+                            return Ok(None)
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    let packet = &self.buf[..n];
+                    let reply = match (self.target, from.ip()) {
+                        (IpAddr::V4(target), _) => parse_v4(packet, target, self.local_port),
+                        (IpAddr::V6(target), IpAddr::V6(source)) => {
+                            parse_v6(packet, source, target, self.local_port)
+                        }
+                        _ => None,
+                    };
+                    reply.filter(|reply| {
+                        reply.port == sent.port
+                            && (self.flow.is_none() || reply.checksum == sent.checksum)
+                    })
+                }
+                #[cfg(target_os = "linux")]
+                Receiver::ErrQueue => {
+                    let fd = self.probe.as_raw_fd();
+                    match errqueue::wait(fd, remaining)? {
+                        Readiness::Timeout => return Ok(None),
+                        Readiness::Data => {
+                            // a UDP answer from the target, the ICMP error is what counts
+                            self.probe.recv(&mut self.buf)?;
+                            None
+                        }
+                        Readiness::Error => {
+                            let mut payload = [0u8; PAYLOAD];
+                            errqueue::recv(fd, &mut payload)?
+                                .filter(|_| payload == sent.payload)
+                                .and_then(|ee| {
+                                    Some(Reply {
+                                        addr: ee.offender?,
+                                        icmp_type: ee.icmp_type,
+                                        icmp_code: ee.icmp_code,
+                                        port: sent.port,
+                                        checksum: sent.checksum,
+                                        reply_ttl: None,
+                                        extensions: Extensions::default(),
+                                    })
+                                })
+                        }
+                    }
+                }
+            };
+            if reply.is_some() {
+                return Ok(reply);
+            }
+        }
+    }
+
+    fn probe(&mut self, ttl: u8, seq: u16, timeout: Duration) -> io::Result<Hop> {
+        self.set_hops(ttl)?;
+        // 0 and 0xffff both mean "no checksum" on the wire
+        let checksum = seq % 0xfffe + 1;
+        let start = Instant::now();
+        let sent = match self.flow {
+            Some((dst, src)) => {
+                let payload = paris_payload(src, dst, checksum);
+                self.probe.send(&payload)?;
+                Sent {
+                    port: dst.port(),
+                    checksum,
+                    payload,
+                }
+            }
+            None => {
+                let port = PORT.wrapping_add(seq);
+                let mut payload = [0u8; PAYLOAD];
+                payload[2..4].copy_from_slice(&seq.to_be_bytes());
+                self.probe
+                    .send_to(&payload, SocketAddr::new(self.target, port))?;
+                Sent {
+                    port,
+                    checksum,
+                    payload,
+                }
+            }
+        };
+        let deadline = start + timeout;
+        if let Some(reply) = self.receive(deadline, &sent)? {
+            let reached = reply.addr == self.target;
+            let unreachable = reply.unreachable().filter(|_| !reached);
+            return Ok(Hop {
+                hop: ttl,
+                addr: Some(reply.addr),
+                rtt: Some(start.elapsed()),
+                reply_ttl: reply.reply_ttl,
+                reached,
+                unreachable,
+                extensions: reply.extensions,
+            });
+        }
+        Ok(Hop {
+            hop: ttl,
+            addr: None,
+            rtt: None,
+            reply_ttl: None,
+            reached: false,
+            unreachable: None,
+            extensions: Extensions::default(),
+        })
+    }
+}
+
+/// Two traces took the same path when the addresses of their hops match, a hop that didn't
+/// answer one of them matches any address since a lost reply doesn't make a new path.
+pub fn same_path(a: &[Hop], b: &[Hop]) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    short
+        .iter()
+        .zip(long)
+        .all(|(x, y)| x.addr.is_none() || y.addr.is_none() || x.addr == y.addr)
+        && long[short.len()..].iter().all(|hop| hop.addr.is_none())
+}
+
+/// Traces the path to `target` with one UDP probe per hop, `on_hop` is called as hops are
+/// found and stops the trace by returning `false`.
+///
+/// Tracing several flows of the same target at once reveals the paths of load balancers.
+/// Probes and the raw socket reading the answers are bound to `source`.
+///
+/// The ICMP answers are read from a raw socket when it is allowed, on Linux they come from
+/// the error queue of the probe socket otherwise, without the ICMP extensions. Run it on a
+/// blocking thread.
+pub fn trace(
+    target: IpAddr,
+    options: &Options,
+    source: &Source,
+    mut on_hop: impl FnMut(Hop) -> bool,
+) -> io::Result<()> {
+    let mut tracer = Tracer::new(target, options.flow, source)?;
+    for (seq, ttl) in (options.first_hop.max(1)..=options.max_hops).enumerate() {
+        let hop = tracer.probe(ttl, seq as u16, options.timeout)?;
+        let done = hop.reached || hop.unreachable.is_some();
+        if !on_hop(hop) || done {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL_PORT: u16 = 40000;
+    const TARGET_V4: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const TARGET_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const HOP_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0xfe);
+
+    fn object(class: u8, c_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut object = ((payload.len() + 4) as u16).to_be_bytes().to_vec();
+        object.extend([class, c_type]);
+        object.extend(payload);
+        object
+    }
+
+    fn mpls_object() -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend((16001u32 << 12 | 1).to_be_bytes());
+        payload.extend((24u32 << 12 | 5 << 9 | 0x100 | 1).to_be_bytes());
+        object(CLASS_MPLS, 1, &payload)
+    }
+
+    /// Incoming interface with its ifindex, IPv4 address, name and MTU.
+    fn interface_object() -> Vec<u8> {
+        let mut payload = 7u32.to_be_bytes().to_vec();
+        payload.extend([0, 1, 0, 0, 10, 0, 0, 1]);
+        payload.extend([8, b'e', b't', b'h', b'0', 0, 0, 0]);
+        payload.extend(1500u32.to_be_bytes());
+        object(CLASS_INTERFACE, 0x0f, &payload)
+    }
+
+    fn extension(objects: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = vec![EXTENSION_VERSION << 4, 0, 0, 0];
+        objects.iter().for_each(|object| buf.extend(object));
+        buf
+    }
+
+    fn udp(port: u16) -> Vec<u8> {
+        let mut udp = LOCAL_PORT.to_be_bytes().to_vec();
+        udp.extend(port.to_be_bytes());
+        udp.extend((8 + PAYLOAD as u16).to_be_bytes());
+        udp.extend(0x1234u16.to_be_bytes());
+        udp
+    }
+
+    /// An ICMP time exceeded from 10.0.0.1 quoting a probe to the target on `port`, the
+    /// original datagram is padded to 128 bytes and followed by `extension`.
+    fn time_exceeded_v4(port: u16, with_length: bool, extension: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 250, 1, 0, 0, 10, 0, 0, 1];
+        packet.extend(TARGET_V4.octets());
+        let length = if with_length { 32 } else { 0 };
+        packet.extend([ICMP_TIME_EXCEEDED, 0, 0, 0, 0, length, 0, 0]);
+        let mut quoted = vec![0x45, 0, 0, 60, 0, 0, 0, 0, 1, IPPROTO_UDP, 0, 0, 0, 0, 0, 0];
+        quoted.extend(TARGET_V4.octets());
+        quoted.extend(udp(port));
+        quoted.resize(COMPAT_ORIGINAL_LENGTH, 0);
+        packet.extend(quoted);
+        packet.extend(extension);
+        packet
+    }
+
+    fn icmp6(icmp_type: u8, port: u16, extension: &[u8]) -> Vec<u8> {
+        let length = if extension.is_empty() { 0 } else { 16 };
+        let mut icmp = vec![icmp_type, 0, 0, 0, length, 0, 0, 0];
+        let mut quoted = vec![0x60, 0, 0, 0, 0, 40, IPPROTO_UDP, 1];
+        quoted.extend([0u8; 16]);
+        quoted.extend(TARGET_V6.octets());
+        quoted.extend(udp(port));
+        quoted.resize(COMPAT_ORIGINAL_LENGTH, 0);
+        icmp.extend(quoted);
+        icmp.extend(extension);
+        icmp
+    }
+
+    fn hops(addrs: &[Option<u8>]) -> Vec<Hop> {
+        addrs
+            .iter()
+            .enumerate()
+            .map(|(idx, addr)| Hop {
+                hop: idx as u8 + 1,
+                addr: addr.map(|last| IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))),
+                rtt: None,
+                reply_ttl: None,
+                reached: false,
+                unreachable: None,
+                extensions: Extensions::default(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_paths() {
+        let path = hops(&[Some(1), Some(2), Some(3)]);
+        assert!(same_path(&path, &path));
+        // lost replies match any hop
+        assert!(same_path(&path, &hops(&[Some(1), None, Some(3)])));
+        assert!(same_path(&hops(&[None, None, None]), &path));
+        assert!(same_path(
+            &path,
+            &hops(&[Some(1), Some(2), Some(3), None, None])
+        ));
+        assert!(!same_path(&path, &hops(&[Some(1), Some(4), Some(3)])));
+        assert!(!same_path(&path, &hops(&[Some(1), None, Some(3), Some(5)])));
+    }
+
+    #[test]
+    fn mpls_stack() {
+        let labels = parse_mpls(&mpls_object()[4..]);
+        assert_eq!(labels.len(), 2);
+        assert_eq!(
+            (
+                labels[0].label,
+                labels[0].tc,
+                labels[0].bottom,
+                labels[0].ttl
+            ),
+            (16001, 0, false, 1)
+        );
+        assert_eq!(
+            (
+                labels[1].label,
+                labels[1].tc,
+                labels[1].bottom,
+                labels[1].ttl
+            ),
+            (24, 5, true, 1)
+        );
+        // a partial entry is ignored
+        assert_eq!(parse_mpls(&mpls_object()[4..10]).len(), 1);
+    }
+
+    #[test]
+    fn interface_with_name_and_mtu() {
+        let object = interface_object();
+        let interface = parse_interface(object[3], &object[4..]).unwrap();
+        assert_eq!(interface.role, "incoming");
+        assert_eq!(interface.ifindex, Some(7));
+        assert_eq!(interface.addr, Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert_eq!(interface.name.as_deref(), Some("eth0"));
+        assert_eq!(interface.mtu, Some(1500));
+
+        // outgoing with only an ipv6 address
+        let mut payload = vec![0, 2, 0, 0];
+        payload.extend(HOP_V6.octets());
+        let interface = parse_interface(0x84, &payload).unwrap();
+        assert_eq!(interface.role, "outgoing");
+        assert_eq!(interface.addr, Some(IpAddr::V6(HOP_V6)));
+        assert_eq!((interface.ifindex, interface.mtu), (None, None));
+    }
+
+    #[test]
+    fn truncated_interface() {
+        let object = interface_object();
+        for len in [2, 6, 14, 20] {
+            assert!(parse_interface(object[3], &object[4..4 + len]).is_none());
+        }
+        // unknown address family
+        assert!(parse_interface(0x04, &[0, 9, 0, 0, 1, 2, 3, 4]).is_none());
+    }
+
+    #[test]
+    fn extension_objects() {
+        let extensions =
+            parse_extensions(&extension(&[mpls_object(), interface_object()])).unwrap();
+        assert_eq!(extensions.mpls.len(), 2);
+        assert_eq!(extensions.interfaces.len(), 1);
+
+        assert!(parse_extensions(&[0x10, 0, 0, 0]).is_none());
+        assert!(parse_extensions(&[EXTENSION_VERSION << 4, 0]).is_none());
+    }
+
+    #[test]
+    fn truncated_extension_objects() {
+        // the second object claims more bytes than are left, the first one is kept
+        let mut buf = extension(&[mpls_object(), interface_object()]);
+        buf.truncate(buf.len() - 4);
+        let extensions = parse_extensions(&buf).unwrap();
+        assert_eq!(extensions.mpls.len(), 2);
+        assert!(extensions.interfaces.is_empty());
+
+        // an object shorter than its own header stops the parsing
+        let mut buf = extension(&[vec![0, 2, CLASS_MPLS, 1]]);
+        buf.extend(mpls_object());
+        assert!(parse_extensions(&buf).unwrap().mpls.is_empty());
+    }
+
+    #[test]
+    fn v4_time_exceeded() {
+        let ext = extension(&[mpls_object(), interface_object()]);
+        for with_length in [true, false] {
+            let packet = time_exceeded_v4(PORT, with_length, &ext);
+            let reply = parse_v4(&packet, TARGET_V4, LOCAL_PORT).unwrap();
+            assert_eq!(reply.addr, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+            assert_eq!((reply.port, reply.checksum), (PORT, 0x1234));
+            assert_eq!(reply.reply_ttl, Some(250));
+            assert_eq!(reply.unreachable(), None);
+            assert_eq!(reply.extensions.mpls.len(), 2);
+            assert_eq!(reply.extensions.interfaces.len(), 1);
+        }
+    }
+
+    #[test]
+    fn v4_rejects() {
+        let packet = time_exceeded_v4(PORT, true, &[]);
+        assert!(parse_v4(&packet, Ipv4Addr::new(192, 0, 2, 2), LOCAL_PORT).is_none());
+        assert!(parse_v4(&packet, TARGET_V4, LOCAL_PORT + 1).is_none());
+        // the quoted UDP header is cut off
+        assert!(parse_v4(&packet[..20 + 8 + 24], TARGET_V4, LOCAL_PORT).is_none());
+        let mut echo = packet.clone();
+        echo[20] = 0;
+        assert!(parse_v4(&echo, TARGET_V4, LOCAL_PORT).is_none());
+        // no extensions after a quote without them
+        let reply = parse_v4(&packet, TARGET_V4, LOCAL_PORT).unwrap();
+        assert!(reply.extensions.mpls.is_empty());
+    }
+
+    #[test]
+    fn v6_time_exceeded() {
+        let icmp = icmp6(ICMP6_TIME_EXCEEDED, PORT, &extension(&[mpls_object()]));
+        let reply = parse_v6(&icmp, HOP_V6, TARGET_V6, LOCAL_PORT).unwrap();
+        assert_eq!(reply.addr, IpAddr::V6(HOP_V6));
+        assert_eq!(reply.port, PORT);
+        assert_eq!(reply.reply_ttl, None);
+        assert_eq!(reply.extensions.mpls.len(), 2);
+        // type 3 is a time exceeded in ICMPv6, not a destination unreachable
+        assert_eq!(reply.unreachable(), None);
+
+        let icmp = icmp6(ICMP6_DST_UNREACH, PORT, &[]);
+        let reply = parse_v6(&icmp, HOP_V6, TARGET_V6, LOCAL_PORT).unwrap();
+        assert_eq!(reply.unreachable(), Some(0));
+        assert!(reply.extensions.mpls.is_empty());
+
+        assert!(parse_v6(&icmp[..40], HOP_V6, TARGET_V6, LOCAL_PORT).is_none());
+        assert!(parse_v6(&icmp6(128, PORT, &[]), HOP_V6, TARGET_V6, LOCAL_PORT).is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::app::{run_job, EVENTS};
+use crate::constant::VERSION;
+use crate::errors::SocketIOError;
+use crate::job::Job;
+use crate::status;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+type Running = Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>;
+
+/// Cancels the jobs of a connection once it is gone, their results have nowhere to go.
+struct CancelOnDrop(Running);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        for cancelled in self.0.lock().unwrap().values() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Keeps a websocket to the server open and runs the jobs received over it.
+///
+/// Requests are `{"id": "...", "event": "ping", "data": {...}}`, every result is sent back as
+/// `{"id": "...", "event": "ping", "data": {...}}` followed by `{"id": "...", "event": "done"}`.
+/// A request can be stopped with `{"id": "...", "event": "cancel"}`.
+pub async fn run(url: String, api_key: String, endpoint: Option<String>) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match connect(&url, &api_key, endpoint.as_deref()).await {
+            Ok(_) => {
+                info!("tunnel to {} closed", url);
+                backoff = Duration::from_secs(1);
+            }
+            Err(e) => error!("tunnel to {} failed: {}", url, e),
+        }
+        status::record_tunnel(false);
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn connect(
+    url: &str,
+    api_key: &str,
+    endpoint: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        "Authorization",
+        HeaderValue::from_str(&format!("Bearer {}", api_key))?,
+    );
+    let (ws, _) = connect_async(request).await?;
+    info!("tunnel connected to {}", url);
+    status::record_tunnel(true);
+    let (mut write, mut read) = ws.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Value>();
+    let running: Running = Arc::new(Mutex::new(HashMap::new()));
+    let _cancel = CancelOnDrop(running.clone());
+    let hello = json!({
+        "event": "hello",
+        "data": {
+            "version": VERSION,
+            "endpoint": endpoint,
+        },
+    });
+    write.send(Message::Text(hello.to_string())).await?;
+    let mut keepalive = time::interval(KEEPALIVE);
+    loop {
+        tokio::select! {
+            msg = read.next() => match msg {
+                Some(Ok(Message::Text(text))) => handle(&text, &tx, &running),
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+            Some(res) = rx.recv() => write.send(Message::Text(res.to_string())).await?,
+            _ = keepalive.tick() => write.send(Message::Ping(Vec::new())).await?,
+        }
+    }
+}
+
+fn handle(text: &str, tx: &UnboundedSender<Value>, running: &Running) {
+    let msg: Value = match serde_json::from_str(text) {
+        Ok(msg) => msg,
+        Err(e) => {
+            error!("invalid tunnel message: {}", e);
+            return;
+        }
+    };
+    debug!("receive tunnel request: {}", msg);
+    let id = msg["id"].as_str().unwrap_or_default().to_string();
+    let event = msg["event"].as_str().unwrap_or_default();
+    if event == "cancel" {
+        if let Some(cancelled) = running.lock().unwrap().get(&id) {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+        return;
+    }
+    let Some(kind) = EVENTS.iter().find(|e| **e == event).copied() else {
+        tx.send(json!({
+            "id": id,
+            "event": event,
+            "data": {
+                "error": SocketIOError::ErrUnknownEvent
+            },
+        }))
+        .ok();
+        return;
+    };
+    let data = msg["data"].clone();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    running
+        .lock()
+        .unwrap()
+        .insert(id.clone(), cancelled.clone());
+    let job = Job::tunnel(kind, id.clone(), tx.clone(), cancelled, &data);
+    let tx = tx.clone();
+    let running = running.clone();
+    tokio::spawn(async move {
+        run_job(job, data).await;
+        running.lock().unwrap().remove(&id);
+        tx.send(json!({ "id": id, "event": "done" })).ok();
+    });
+}
@@ -0,0 +1,192 @@
+use crate::ntp;
+use std::net::Ipv6Addr;
+use std::time::SystemTime;
+use tokio::net::UdpSocket;
+use tracing::{debug, error, info};
+
+/// TWAMP-Test port from RFC 5357, TWAMP-light has no control protocol so any port works.
+pub const PORT: u16 = 862;
+/// Sequence number, timestamp and error estimate of an unauthenticated sender packet.
+pub const SENDER_HEADER: usize = 14;
+/// Reflector packet up to the sender TTL, see RFC 5357 section 4.2.1.
+const REFLECTOR_HEADER: usize = 41;
+pub const MAX_SIZE: usize = 1472;
+/// Error estimate with the S bit unset, the clock is not known to be synchronized to UTC.
+const ERROR_ESTIMATE: [u8; 2] = [0x00, 0x01];
+
+/// Timestamps of a reflected test packet, seconds since the unix epoch.
+pub struct Reply {
+    pub seq: u32,
+    pub reflector_seq: u32,
+    /// Sent by us.
+    pub t1: f64,
+    /// Received by the reflector.
+    pub t2: f64,
+    /// Sent back by the reflector.
+    pub t3: f64,
+    /// Received by us.
+    pub t4: f64,
+}
+
+impl Reply {
+    /// Round-trip time in seconds, excluding the processing time of the reflector.
+    pub fn rtt(&self) -> f64 {
+        (self.t4 - self.t1) - (self.t3 - self.t2)
+    }
+
+    /// One-way delay to the reflector in seconds, only meaningful with synchronized clocks.
+    pub fn forward(&self) -> f64 {
+        self.t2 - self.t1
+    }
+
+    /// One-way delay back from the reflector in seconds, only meaningful with synchronized clocks.
+    pub fn backward(&self) -> f64 {
+        self.t4 - self.t3
+    }
+
+    pub fn processing(&self) -> f64 {
+        self.t3 - self.t2
+    }
+}
+
+/// Interarrival jitter from RFC 3550, the offset of the clocks cancels out.
+#[derive(Default)]
+pub struct Jitter {
+    last: Option<f64>,
+    pub value: f64,
+}
+
+impl Jitter {
+    pub fn update(&mut self, transit: f64) {
+        if let Some(last) = self.last {
+            self.value += ((transit - last).abs() - self.value) / 16.0;
+        }
+        self.last = Some(transit);
+    }
+}
+
+/// Builds an unauthenticated sender packet padded with zeros to `size` bytes, at least as
+/// long as the reflector packet since reflectors never answer with more than they receive.
+pub fn request(seq: u32, now: SystemTime, size: usize) -> Vec<u8> {
+    let mut packet = vec![0u8; size.clamp(REFLECTOR_HEADER, MAX_SIZE)];
+    packet[0..4].copy_from_slice(&seq.to_be_bytes());
+    packet[4..12].copy_from_slice(&ntp::to_timestamp(now).to_be_bytes());
+    packet[12..14].copy_from_slice(&ERROR_ESTIMATE);
+    packet
+}
+
+/// Parses a reflector packet, `None` if it is too short to be one.
+pub fn parse(response: &[u8], received: SystemTime) -> Option<Reply> {
+    if response.len() < REFLECTOR_HEADER {
+        return None;
+    }
+    let timestamp = |range: std::ops::Range<usize>| {
+        ntp::from_timestamp(u64::from_be_bytes(response[range].try_into().unwrap()))
+    };
+    Some(Reply {
+        seq: u32::from_be_bytes(response[24..28].try_into().unwrap()),
+        reflector_seq: u32::from_be_bytes(response[0..4].try_into().unwrap()),
+        t1: timestamp(28..36),
+        t2: timestamp(16..24),
+        t3: timestamp(4..12),
+        t4: ntp::unix_seconds(received),
+    })
+}
+
+/// Answers a sender packet with a reflector packet of the same size, `None` for packets too
+/// short to hold one so the reflector can't be used to amplify traffic.
+fn reflect(request: &[u8], seq: u32, received: SystemTime) -> Option<Vec<u8>> {
+    if request.len() < REFLECTOR_HEADER {
+        return None;
+    }
+    // keep the size of the sender packet so both directions carry the same load
+    let mut packet = vec![0u8; request.len()];
+    packet[0..4].copy_from_slice(&seq.to_be_bytes());
+    packet[12..14].copy_from_slice(&ERROR_ESTIMATE);
+    packet[16..24].copy_from_slice(&ntp::to_timestamp(received).to_be_bytes());
+    packet[24..38].copy_from_slice(&request[..SENDER_HEADER]);
+    // the sender TTL at byte 40 needs IP_RECVTTL and is left at 0
+    packet[4..12].copy_from_slice(&ntp::to_timestamp(SystemTime::now()).to_be_bytes());
+    Some(packet)
+}
+
+/// Reflects TWAMP-light test packets from other agents and TWAMP senders.
+pub async fn serve(port: u16) {
+    let sock = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+        Ok(sock) => sock,
+        Err(e) => {
+            error!("twamp reflector failed to listen on {}: {}", port, e);
+            return;
+        }
+    };
+    info!("twamp reflector listening on port {}", port);
+    let mut buf = [0u8; MAX_SIZE];
+    let mut seq = 0u32;
+    loop {
+        let (n, peer) = match sock.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(e) => {
+                debug!("twamp reflector receive failed: {}", e);
+                continue;
+            }
+        };
+        let received = SystemTime::now();
+        let Some(packet) = reflect(&buf[..n], seq, received) else {
+            continue;
+        };
+        seq = seq.wrapping_add(1);
+        if let Err(e) = sock.send_to(&packet, peer).await {
+            debug!("twamp reflector send to {} failed: {}", peer, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn at(secs: f64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn round_trip() {
+        let sent = request(7, at(1_700_000_000.25), 50);
+        assert_eq!(sent.len(), 50);
+        let reflected = reflect(&sent, 3, at(1_700_000_000.5)).unwrap();
+        assert_eq!(reflected.len(), sent.len());
+        let reply = parse(&reflected, at(1_700_000_001.0)).unwrap();
+        assert_eq!(reply.seq, 7);
+        assert_eq!(reply.reflector_seq, 3);
+        assert!((reply.t1 - 1_700_000_000.25).abs() < 1e-6);
+        assert!((reply.t2 - 1_700_000_000.5).abs() < 1e-6);
+        assert_eq!(reply.t4, 1_700_000_001.0);
+        assert!(reply.t3 >= reply.t2);
+    }
+
+    #[test]
+    fn request_sizes() {
+        let now = SystemTime::now();
+        assert_eq!(request(0, now, 0).len(), REFLECTOR_HEADER);
+        assert_eq!(request(0, now, 100_000).len(), MAX_SIZE);
+        let reflected = reflect(&request(0, now, 0), 0, now).unwrap();
+        assert_eq!(reflected.len(), REFLECTOR_HEADER);
+        assert!(parse(&reflected, now).is_some());
+    }
+
+    #[test]
+    fn no_amplification() {
+        let now = SystemTime::now();
+        assert!(reflect(&[0u8; SENDER_HEADER], 0, now).is_none());
+        assert!(reflect(&[0u8; REFLECTOR_HEADER - 1], 0, now).is_none());
+        for size in [REFLECTOR_HEADER, 64, MAX_SIZE] {
+            assert_eq!(reflect(&vec![0u8; size], 0, now).unwrap().len(), size);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_short() {
+        assert!(parse(&[0u8; REFLECTOR_HEADER - 1], SystemTime::now()).is_none());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::ntp;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::random;
+use std::time::SystemTime;
+
+/// Built-in payloads for common UDP services, every probe gets fresh ids.
+#[derive(Clone, Copy)]
+pub enum Template {
+    /// Query for the root NS records.
+    Dns,
+    /// SNTP v4 client request.
+    Ntp,
+    /// STUN binding request.
+    Stun,
+    /// SNMP v2c get of sysDescr.0 with the `public` community.
+    Snmp,
+    /// QUIC long header packet with a reserved version, servers answer with version negotiation.
+    Quic,
+}
+
+impl Template {
+    pub fn parse(name: &str) -> Option<Template> {
+        match name {
+            "dns" => Some(Template::Dns),
+            "ntp" => Some(Template::Ntp),
+            "stun" => Some(Template::Stun),
+            "snmp" => Some(Template::Snmp),
+            "quic" => Some(Template::Quic),
+            _ => None,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Template::Dns => 53,
+            Template::Ntp => ntp::PORT,
+            Template::Stun => 3478,
+            Template::Snmp => 161,
+            Template::Quic => 443,
+        }
+    }
+
+    pub fn payload(&self) -> Vec<u8> {
+        match self {
+            Template::Dns => {
+                let id: u16 = random();
+                let mut payload = id.to_be_bytes().to_vec();
+                // recursion desired, one question: ". IN NS"
+                payload.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+                payload.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x01]);
+                payload
+            }
+            Template::Ntp => ntp::request(SystemTime::now()).to_vec(),
+            Template::Stun => {
+                let mut payload = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42];
+                payload.extend_from_slice(&random::<[u8; 12]>());
+                payload
+            }
+            Template::Snmp => {
+                let mut payload = vec![
+                    0x30, 0x29, 0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c',
+                    0xa0, 0x1c, 0x02, 0x04,
+                ];
+                payload.extend_from_slice(&(random::<u32>() & 0x7fff_ffff).to_be_bytes());
+                payload.extend_from_slice(&[
+                    0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30, 0x0e, 0x30, 0x0c, 0x06, 0x08, 0x2b,
+                    0x06, 0x01, 0x02, 0x01, 0x01, 0x01, 0x00, 0x05, 0x00,
+                ]);
+                payload
+            }
+            Template::Quic => {
+                let mut payload = vec![0xc0 | (random::<u8>() & 0x0f)];
+                payload.extend_from_slice(&[0x1a, 0x2a, 0x3a, 0x4a]);
+                payload.push(8);
+                payload.extend_from_slice(&random::<[u8; 8]>());
+                payload.push(8);
+                payload.extend_from_slice(&random::<[u8; 8]>());
+                // servers drop client initial packets smaller than 1200 bytes
+                payload.resize(1200, 0);
+                payload
+            }
+        }
+    }
+
+    /// Whether `response` answers `request` rather than being a stray datagram.
+    pub fn matches(&self, request: &[u8], response: &[u8]) -> bool {
+        match self {
+            Template::Dns => response.len() >= 12 && response[..2] == request[..2],
+            Template::Ntp => response.len() >= 48 && response[24..32] == request[40..48],
+            Template::Stun => response.len() >= 20 && response[8..20] == request[8..20],
+            Template::Snmp => response.first() == Some(&0x30),
+            Template::Quic => response.len() >= 5 && response[1..5] == [0, 0, 0, 0],
+        }
+    }
+}
+
+pub fn decode(payload: &str, encoding: &str) -> Option<Vec<u8>> {
+    match encoding {
+        "hex" => hex::decode(payload.replace([' ', ':'], "")).ok(),
+        "base64" => BASE64.decode(payload).ok(),
+        _ => None,
+    }
+}
+
+pub fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_templates() {
+        assert_eq!(Template::parse("dns").map(|t| t.port()), Some(53));
+        assert_eq!(Template::parse("ntp").map(|t| t.port()), Some(123));
+        assert_eq!(Template::parse("stun").map(|t| t.port()), Some(3478));
+        assert_eq!(Template::parse("snmp").map(|t| t.port()), Some(161));
+        assert_eq!(Template::parse("quic").map(|t| t.port()), Some(443));
+        assert!(Template::parse("DNS").is_none());
+        assert!(Template::parse("").is_none());
+    }
+
+    #[test]
+    fn dns_matches_own_id() {
+        let request = Template::Dns.payload();
+        assert_eq!(request.len(), 17);
+        let mut response = request.clone();
+        response[2] |= 0x80;
+        assert!(Template::Dns.matches(&request, &response));
+        response[0] ^= 0xff;
+        assert!(!Template::Dns.matches(&request, &response));
+        assert!(!Template::Dns.matches(&request, &request[..11]));
+    }
+
+    #[test]
+    fn ntp_matches_origin_timestamp() {
+        let request = Template::Ntp.payload();
+        let mut response = vec![0u8; 48];
+        response[24..32].copy_from_slice(&request[40..48]);
+        assert!(Template::Ntp.matches(&request, &response));
+        response[31] ^= 1;
+        assert!(!Template::Ntp.matches(&request, &response));
+    }
+
+    #[test]
+    fn stun_matches_transaction_id() {
+        let request = Template::Stun.payload();
+        assert_eq!(request.len(), 20);
+        assert_eq!(request[4..8], [0x21, 0x12, 0xa4, 0x42]);
+        let mut response = vec![0x01, 0x01, 0x00, 0x00];
+        response.extend_from_slice(&request[4..20]);
+        assert!(Template::Stun.matches(&request, &response));
+        response[19] ^= 1;
+        assert!(!Template::Stun.matches(&request, &response));
+    }
+
+    #[test]
+    fn snmp_payload_is_a_sequence() {
+        let request = Template::Snmp.payload();
+        // the outer sequence length covers the rest of the message
+        assert_eq!(request[0], 0x30);
+        assert_eq!(request[1] as usize, request.len() - 2);
+        assert!(Template::Snmp.matches(&request, &[0x30, 0x00]));
+        assert!(!Template::Snmp.matches(&request, &[0x31]));
+    }
+
+    #[test]
+    fn quic_detects_version_negotiation() {
+        let request = Template::Quic.payload();
+        assert_eq!(request.len(), 1200);
+        assert_eq!(request[0] & 0xf0, 0xc0);
+        assert!(Template::Quic.matches(&request, &[0x80, 0, 0, 0, 0, 8]));
+        assert!(!Template::Quic.matches(&request, &[0xc0, 0, 0, 0, 1, 8]));
+        assert!(!Template::Quic.matches(&request, &[0x80, 0, 0]));
+    }
+
+    #[test]
+    fn decode_payloads() {
+        assert_eq!(
+            decode("de ad:BE ef", "hex"),
+            Some(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(decode("aGk=", "base64"), Some(b"hi".to_vec()));
+        assert_eq!(decode("zz", "hex"), None);
+        assert_eq!(decode("aGk=", "utf8"), None);
+    }
+
+    #[test]
+    fn contains_needle() {
+        assert!(contains(b"SSH-2.0-OpenSSH", b"OpenSSH"));
+        assert!(contains(b"abc", b""));
+        assert!(!contains(b"ab", b"abc"));
+    }
+}
@@ -1,12 +1,13 @@
 use crate::cli::Cli;
 use crate::constant::{V4_SERVER, V6_SERVER};
 use crate::{api::add_agent, constant::VERSION};
+use crate::{metrics, status};
 use tracing::error;
 
 pub async fn add_agent_with_args(args: Cli, ip_type: &str, init: bool) -> bool {
     match ip_type {
         "ipv4" => {
-            add_agent(
+            let success = add_agent(
                 args.ipv4_server.unwrap_or(V4_SERVER.to_string()),
                 args.port,
                 args.api_key,
@@ -15,10 +16,15 @@ pub async fn add_agent_with_args(args: Cli, ip_type: &str, init: bool) -> bool {
                 "ipv4",
                 init,
             )
-            .await
+            .await;
+            status::record_registration("ipv4", success);
+            metrics::REGISTRATIONS
+                .with_label_values(&["ipv4", metrics::outcome(success)])
+                .inc();
+            success
         }
         "ipv6" => {
-            add_agent(
+            let success = add_agent(
                 args.ipv6_server.unwrap_or(V6_SERVER.to_string()),
                 args.port,
                 args.api_key,
@@ -27,7 +33,12 @@ pub async fn add_agent_with_args(args: Cli, ip_type: &str, init: bool) -> bool {
                 "ipv6",
                 init,
             )
-            .await
+            .await;
+            status::record_registration("ipv6", success);
+            metrics::REGISTRATIONS
+                .with_label_values(&["ipv6", metrics::outcome(success)])
+                .inc();
+            success
         }
         _ => {
             error!("ip_type must be ipv4 or ipv6");